@@ -0,0 +1,158 @@
+//! Copies a file over loopback twice, once through 1MB buffers the way the
+//! default loops do and once with sendfile on the sending side and splice on
+//! the receiving side, and reports time and CPU for each.
+//!
+//!     cargo bench --bench zero_copy
+//!
+//! `SEND_BENCH_MB` sets the file size (default 256).
+
+#[path = "../src/zerocopy.rs"]
+#[allow(dead_code)]
+mod zerocopy;
+
+#[cfg(target_os = "linux")]
+fn main() -> std::io::Result<()> {
+    let mb: u64 = std::env::var("SEND_BENCH_MB")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(256);
+    let dir = std::env::temp_dir().join(format!("send-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let source = dir.join("source");
+    write_source(&source, mb)?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    println!("Copying {} MB over loopback", mb);
+    let result = runtime.block_on(async {
+        // Warm the page cache so both runs read from memory
+        bench::copy(&source, &dir.join("warmup"), false).await?;
+        for zero_copy in [false, true] {
+            let target = dir.join("target");
+            let cpu = cpu_time();
+            let started = std::time::Instant::now();
+            bench::copy(&source, &target, zero_copy).await?;
+            let wall = started.elapsed();
+            let cpu = cpu_time() - cpu;
+            if std::fs::metadata(&target)?.len() != mb * 1024 * 1024 {
+                return Err(std::io::Error::other("copy came out the wrong size"));
+            }
+            println!(
+                "{:<10} {:>8.2} s {:>10.1} MB/s  CPU {:>6.2} s ({:.0}% of wall time)",
+                if zero_copy { "zero-copy" } else { "buffered" },
+                wall.as_secs_f64(),
+                mb as f64 / wall.as_secs_f64(),
+                cpu,
+                cpu / wall.as_secs_f64() * 100.0
+            );
+        }
+        Ok(())
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("The zero-copy paths are Linux only; nothing to compare here.");
+}
+
+#[cfg(target_os = "linux")]
+fn write_source(path: &std::path::Path, mb: u64) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let block: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for _ in 0..mb {
+        file.write_all(&block)?;
+    }
+    file.flush()
+}
+
+/// User plus system time this process has used, in seconds.
+#[cfg(target_os = "linux")]
+fn cpu_time() -> f64 {
+    // SAFETY: getrusage only writes into the struct we hand it
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+        usage
+    };
+    let secs = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    secs(usage.ru_utime) + secs(usage.ru_stime)
+}
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use super::zerocopy::{self, Splicer};
+    use std::io;
+    use std::path::Path;
+    use tokio::fs::File;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    const CHUNK: usize = 1024 * 1024;
+
+    pub async fn copy(source: &Path, target: &Path, zero_copy: bool) -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let target = target.to_path_buf();
+        let receiver = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await?;
+            receive(socket, &target, zero_copy).await
+        });
+        let socket = TcpStream::connect(addr).await?;
+        send(socket, source, zero_copy).await?;
+        receiver.await?
+    }
+
+    async fn send(mut socket: TcpStream, source: &Path, zero_copy: bool) -> io::Result<()> {
+        let mut file = File::open(source).await?;
+        let size = file.metadata().await?.len();
+        if zero_copy {
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(CHUNK as u64) as usize;
+                if zerocopy::send_file(&socket, &file, &mut offset, len).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        } else {
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                socket.write_all(&buf[..n]).await?;
+            }
+        }
+        socket.shutdown().await
+    }
+
+    async fn receive(mut socket: TcpStream, target: &Path, zero_copy: bool) -> io::Result<()> {
+        let mut file = File::create(target).await?;
+        if zero_copy {
+            let splicer = Splicer::new()?;
+            let mut offset = 0;
+            loop {
+                let n = splicer.fill(&socket, CHUNK).await?;
+                if n == 0 {
+                    break;
+                }
+                splicer.drain(&file, &mut offset, n)?;
+            }
+        } else {
+            let mut buf = vec![0u8; CHUNK];
+            loop {
+                let n = socket.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                file.write_all(&buf[..n]).await?;
+            }
+            file.flush().await?;
+        }
+        Ok(())
+    }
+}
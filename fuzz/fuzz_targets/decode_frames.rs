@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes through both frame decoders.
+//!
+//! Run with `cargo fuzz run decode_frames` from the repository root.
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/protocol.rs"]
+mod protocol;
+
+#[allow(dead_code)]
+#[path = "../../src/codec.rs"]
+mod codec;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    futures::executor::block_on(async {
+        // Decode as many frames as the input holds, like a live connection would
+        let mut reader = data;
+        while codec::read_client(&mut reader).await.is_ok() {}
+
+        let mut reader = data;
+        while codec::read_server(&mut reader).await.is_ok() {}
+    });
+});
@@ -1,4 +1,12 @@
-use clap::{Parser, Subcommand};
+use crate::access::Cidr;
+use crate::client::ChangePolicy;
+use crate::durable::Durability;
+use crate::protocol::ConflictPolicy;
+use crate::server::SpaceCheck;
+use crate::throttle::{Schedule, parse_rate};
+use crate::uring::IoBackend;
+use clap::{Args, Parser, Subcommand};
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -16,6 +24,87 @@ pub enum Commands {
         path: PathBuf,
         /// Port to listen on
         port: u16,
+        /// Seconds a read or write may stall before the client is dropped (0 = never)
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+        /// Seconds a client may stay silent between files before it is dropped (0 = never)
+        #[arg(long, default_value_t = 300)]
+        idle_timeout: u64,
+        /// TCP keepalive idle time in seconds (0 = off)
+        #[arg(long, default_value_t = 30)]
+        keepalive: u64,
+        /// Address to listen on, e.g. 192.168.1.10 or :: for IPv6
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: IpAddr,
+        /// Only accept connections arriving on this network interface (Linux)
+        #[arg(long)]
+        interface: Option<String>,
+        /// Maximum number of clients served at once (0 = unlimited)
+        #[arg(long, default_value_t = 0)]
+        max_sessions: usize,
+        /// Only accept clients from these addresses/ranges (e.g. 192.168.1.0/24)
+        #[arg(long)]
+        allow: Vec<Cidr>,
+        /// Refuse clients from these addresses/ranges
+        #[arg(long)]
+        deny: Vec<Cidr>,
+        /// Bandwidth limit per client address (e.g. 50M/s)
+        #[arg(long, value_parser = parse_rate)]
+        client_rate: Option<u64>,
+        /// Maximum number of files accepted from one client address
+        #[arg(long)]
+        client_max_files: Option<u64>,
+        /// Conflict policy for every client, overriding theirs: overwrite,
+        /// skip-existing, overwrite-if-newer, keep-both or backup
+        #[arg(long)]
+        conflict: Option<ConflictPolicy>,
+        /// Keep up to this many earlier versions of each overwritten file in .send-versions
+        #[arg(long)]
+        keep_versions: Option<usize>,
+        /// Keep earlier versions of overwritten files for this many days
+        #[arg(long)]
+        keep_versions_days: Option<u64>,
+        /// Delete partial files nobody resumed within this many hours (0 = keep them)
+        #[arg(long, default_value_t = 168)]
+        stale_partials: u64,
+        /// Sync received files to disk before they get their final name:
+        /// full syncs each file, batch syncs groups of files together
+        #[arg(long, value_enum, default_value_t = Durability::Off, num_args = 0..=1, default_missing_value = "full")]
+        durable: Durability,
+        /// What to do when a client has more to send than the disk can hold
+        #[arg(long, value_enum, default_value_t = SpaceCheck::Warn)]
+        space_check: SpaceCheck,
+        /// Don't reserve disk space for files before writing them (for
+        /// filesystems that handle fallocate badly)
+        #[arg(long)]
+        no_preallocate: bool,
+        /// Always receive through a user-space buffer instead of splicing
+        /// from the socket into files (Linux)
+        #[arg(long)]
+        no_zero_copy: bool,
+        /// 1MB buffers per session that received data can queue in while
+        /// the disk catches up
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+        buffers: u16,
+        /// File I/O for writing received files; io-uring batches the
+        /// open/write/sync/close of small files and renames (Linux, falls
+        /// back to tokio when the kernel can't)
+        #[arg(long, value_enum, default_value_t = IoBackend::Tokio)]
+        io_backend: IoBackend,
+    },
+    /// List or delete partial files kept by the server for resuming
+    Staging {
+        /// Directory the server saves received files to
+        path: PathBuf,
+        #[command(subcommand)]
+        action: StagingAction,
+    },
+    /// List or restore earlier versions kept by `serve --keep-versions`
+    Versions {
+        /// Directory the server saves received files to
+        path: PathBuf,
+        #[command(subcommand)]
+        action: VersionsAction,
     },
     /// Send files/folders
     Push {
@@ -25,9 +114,15 @@ pub enum Commands {
         ip: String,
         /// Target Port
         port: u16,
-        /// Patterns to exclude (e.g. "*.git", "node_modules")
+        /// Patterns to exclude, .gitignore style (e.g. ".git", "node_modules/", "*.log")
         #[arg(short, long)]
         exclude: Vec<String>,
+        /// Also leave out what git ignores: .gitignore files, .git/info/exclude
+        /// and the global excludes file. Saved with the transfer
+        #[arg(long)]
+        respect_gitignore: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     /// List transfer history
     List,
@@ -38,6 +133,12 @@ pub enum Commands {
         /// Update exclude patterns
         #[arg(short, long)]
         exclude: Vec<String>,
+        /// Walk the source again: send new and changed files, mark vanished
+        /// ones as missing
+        #[arg(long)]
+        rescan: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     /// Restart a transfer (re-scan and re-send)
     Restart {
@@ -46,6 +147,8 @@ pub enum Commands {
         /// Update exclude patterns
         #[arg(short, long)]
         exclude: Vec<String>,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     /// Remove a transfer history
     Remove {
@@ -53,3 +156,93 @@ pub enum Commands {
         id: i64,
     },
 }
+
+#[derive(Subcommand)]
+pub enum VersionsAction {
+    /// Show the versions of a file, or every file that has versions
+    List {
+        /// File path relative to the server directory
+        file: Option<String>,
+    },
+    /// Put an earlier version of a file back (the current one is kept as a version)
+    Restore {
+        /// File path relative to the server directory
+        file: String,
+        /// Version to restore (default: the newest)
+        #[arg(long)]
+        version: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum StagingAction {
+    /// Show partial files waiting to be resumed
+    List,
+    /// Delete partial files
+    Purge {
+        /// Only those untouched for at least this many hours
+        #[arg(long)]
+        older_than: Option<u64>,
+        /// Only those of one session
+        #[arg(long)]
+        session: Option<String>,
+    },
+}
+
+/// Options shared by every command that sends files
+#[derive(Args)]
+pub struct TransferArgs {
+    /// Reconnect attempts after a network error before giving up (0 = no retry)
+    #[arg(long, default_value_t = 5)]
+    pub retries: u32,
+    /// Seconds to wait before the first reconnect, doubled after each failure
+    #[arg(long, default_value_t = 1)]
+    pub retry_delay: u64,
+    /// Maximum seconds to wait between reconnect attempts
+    #[arg(long, default_value_t = 60)]
+    pub max_retry_delay: u64,
+    /// Seconds a read or write may stall before the connection counts as lost (0 = never)
+    #[arg(long, default_value_t = 60)]
+    pub timeout: u64,
+    /// Seconds of silence after which a heartbeat is sent to the server (0 = off)
+    #[arg(long, default_value_t = 15)]
+    pub heartbeat: u64,
+    /// TCP keepalive idle time in seconds (0 = off)
+    #[arg(long, default_value_t = 30)]
+    pub keepalive: u64,
+    /// Record files that can't be sent and carry on with the rest
+    #[arg(long)]
+    pub continue_on_error: bool,
+    /// What to do when a file changed size since it was scanned
+    #[arg(long, value_enum, default_value_t = ChangePolicy::Requeue)]
+    pub on_change: ChangePolicy,
+    /// Hold back files modified within this many seconds until they stop changing (0 = off)
+    #[arg(long, default_value_t = 0)]
+    pub stable_for: u64,
+    /// Cap the upload rate, e.g. 50M/s (off = unlimited). Saved with the transfer
+    #[arg(long, value_parser = parse_rate)]
+    pub limit: Option<u64>,
+    /// Rates by time of day, e.g. "08:00-18:00=10M/s,18:00-08:00=off". Outside
+    /// every window --limit applies. Saved with the transfer
+    #[arg(long)]
+    pub schedule: Option<Schedule>,
+    /// What the server should do with a destination file of a different size
+    /// or older than ours: overwrite, skip-existing, overwrite-if-newer, keep-both or backup
+    /// (default overwrite; a policy set on the server wins)
+    #[arg(long)]
+    pub conflict: Option<ConflictPolicy>,
+    /// Always send through a user-space buffer instead of using sendfile
+    /// (Linux)
+    #[arg(long)]
+    pub no_zero_copy: bool,
+    /// 1MB buffers the file being sent is read ahead into
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub buffers: u16,
+    /// Start sending while the source is still being scanned
+    #[arg(long)]
+    pub overlap: bool,
+    /// Directories read at once while scanning; the file order is the same
+    /// for any value
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub scan_threads: u16,
+}
@@ -1,32 +1,56 @@
-use crate::db::TransferLog;
-use crate::protocol::{FileMetadata, ServerResponse};
+use crate::codec::{self, FrameError};
+use crate::db::{FileRecord, TransferLog};
+use crate::ignore::{self, IgnoreRules};
+use crate::net::{self, Timeouts};
+use crate::protocol::{
+    ClientMessage, ConflictPolicy, ErrorCategory, FileMetadata, Hello, ServerResponse,
+};
+use crate::shutdown::{Interrupted, Shutdown};
+use crate::throttle::{Schedule, Throttle};
+use crate::walk::{Walker, unix_mtime};
+use crate::zerocopy;
 use anyhow::{Result, anyhow};
+use std::collections::VecDeque;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
-use tokio::fs::{self, File};
+use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use walkdir::WalkDir;
-
-use glob::Pattern;
+use tokio::sync::mpsc;
 
 pub async fn scan_files(
     source_path: PathBuf,
     log: &TransferLog,
     exclude_patterns: &[String],
-) -> Result<()> {
+    threads: usize,
+    rescan: bool,
+    respect_gitignore: bool,
+    shutdown: &Shutdown,
+) -> Result<Vec<String>> {
     println!("Scanning files (Excludes: {:?})...", exclude_patterns);
-    let walker = WalkDir::new(&source_path);
+    let mut walker = Walker::new(&source_path, threads);
     let mut count = 0;
+    let mut inserts = match rescan {
+        true => log.rescan()?,
+        false => log.bulk_insert(),
+    };
 
-    let patterns: Vec<Pattern> = exclude_patterns
-        .iter()
-        .filter_map(|p| Pattern::new(p).ok())
-        .collect();
+    let mut rules = IgnoreRules::new(exclude_patterns);
+    if respect_gitignore {
+        if let Some(global) = ignore::global_excludes(&source_path).await {
+            rules.load("", &global).await;
+        }
+        rules.load("", &source_path.join(".git/info/exclude")).await;
+    }
 
-    for entry in walker.into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
+    while let Some(entry) = walker.next().await {
+        shutdown.check()?;
+        // Unreadable entries are left out, as they always were
+        let Ok(entry) = entry else {
+            continue;
+        };
+        let path = &entry.path;
 
         let _should_process = true; // Process all, log everything
 
@@ -41,28 +65,22 @@ pub async fn scan_files(
         // Normalize path separators
         let relative_path_clean = relative_path_str.replace("\\", "/");
 
-        // Check exclude patterns
-        if patterns.iter().any(|p| p.matches(&relative_path_clean)) {
-            // println!("Excluded: {}", relative_path_clean);
+        // Check exclude patterns. An excluded directory is never entered,
+        // so nothing under it needs checking
+        if rules.excludes(rule_path(&relative_path_clean), entry.is_dir) {
+            walker.skip_current_dir();
             continue;
         }
+        // A directory's own ignore files cover what is inside it
+        if entry.is_dir {
+            let dir = rule_path(&relative_path_clean);
+            if respect_gitignore {
+                rules.load(dir, &path.join(".gitignore")).await;
+            }
+            rules.load(dir, &path.join(".sendignore")).await;
+        }
 
-        // Also check if any parent directory is excluded for safer skipping?
-        // WalkDir usually recurses. If we exclude "node_modules", we want to skip everything inside it.
-        // Glob matching "node_modules" usually only matches the directory itself if relative_path_clean is exactly "node_modules".
-        // It won't match "node_modules/foo.js".
-        // Users often expect "node_modules" to exclude recursive.
-        // But glob behavior matches string.
-        // If user provides "node_modules" and we have "project/node_modules/file.txt".
-        // We probably want to support standard glob (git-like) if possible, but simplicity first.
-        // If user says "**/node_modules/**" it works.
-        // But let's assume if it matches, we skip.
-
-        let metadata = fs::metadata(path).await?;
-        let is_dir = metadata.is_dir();
-        let size = metadata.len();
-
-        log.add_file(&relative_path_clean, size, is_dir)?;
+        inserts.add(&relative_path_clean, entry.size, entry.is_dir, entry.mtime)?;
         count += 1;
 
         if count % 100 == 0 {
@@ -70,297 +88,1024 @@ pub async fn scan_files(
             std::io::stdout().flush()?;
         }
     }
+    // Files the rules now leave out aren't missing, just not wanted
+    let changes =
+        inserts.finish(|path, is_dir| rules.excludes_with_parents(rule_path(path), is_dir))?;
     println!("\rScanned: {} items. Scan complete.", count);
-    Ok(())
+    if rescan {
+        println!(
+            "Rescan: {} new, {} changed, {} missing.",
+            changes.new, changes.changed, changes.missing
+        );
+    }
+    Ok(rules.found().to_vec())
+}
+
+/// The part of a logged path exclude patterns are matched against: relative
+/// to the source directory, without its name in front.
+fn rule_path(relative_path: &str) -> &str {
+    relative_path.split_once('/').map_or("", |(_, rest)| rest)
+}
+
+/// What to do with a file whose size no longer matches the scan
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChangePolicy {
+    /// Move it to the end of the pass and send the version found then
+    Requeue,
+    /// Send the current version straight away
+    Send,
+    /// Treat it as a failed file
+    Fail,
+}
+
+/// Settings used by `send_pending_files`.
+pub struct SendOptions {
+    /// How many reconnects in a row are attempted before giving up
+    pub retries: u32,
+    /// Delay before the first reconnect, doubled after each failed attempt
+    pub retry_delay: Duration,
+    /// Upper bound for the reconnect delay
+    pub max_retry_delay: Duration,
+    pub timeouts: Timeouts,
+    /// Send a ping when the server hasn't heard from us for this long
+    pub heartbeat: Duration,
+    /// Record per-file errors and move on instead of stopping the transfer
+    pub continue_on_error: bool,
+    pub on_change: ChangePolicy,
+    /// Files modified more recently than this are requeued until they settle
+    pub stable_for: Duration,
+    /// Upload cap in bytes per second (0 = unlimited)
+    pub rate_limit: u64,
+    /// Time-of-day rates that take precedence over `rate_limit`
+    pub schedule: Option<Schedule>,
+    /// Conflict policy to ask the server for
+    pub conflict: Option<ConflictPolicy>,
+    /// Key the server files this transfer's partials under
+    pub session: String,
+    /// Send file data with `sendfile` where the OS supports it
+    pub zero_copy: bool,
+    /// How many 1MB buffers the file may be read ahead into
+    pub buffers: usize,
 }
 
+/// Wraps I/O errors on the socket so they can be told apart from local file
+/// errors and server-side rejections: only these are worth a reconnect.
+#[derive(Debug)]
+struct ConnectionLost(std::io::Error);
+
+impl std::fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection lost: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectionLost {}
+
+/// Socket failures are worth a reconnect; a peer speaking garbage is not.
+fn connection_error(e: FrameError) -> anyhow::Error {
+    match e {
+        FrameError::Io(e) => ConnectionLost(e).into(),
+        other => anyhow!("Protocol error: {}", other),
+    }
+}
+
+/// A file failed part-way through its data, leaving the server waiting for
+/// bytes that will never come. The session is dropped and a new one started.
+#[derive(Debug)]
+struct StreamAborted;
+
+impl std::fmt::Display for StreamAborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream aborted after a read error")
+    }
+}
+
+impl std::error::Error for StreamAborted {}
+
+/// The server's disk filled up. Reconnecting won't help until someone frees
+/// space, so the transfer stops and `resume` picks it up later.
+#[derive(Debug)]
+struct OutOfSpace {
+    message: String,
+    /// The file the server couldn't store, if it said
+    relative_path: Option<String>,
+}
+
+impl std::fmt::Display for OutOfSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for OutOfSpace {}
+
+/// Returned when the transfer ran to the end but some files could not be
+/// sent under `--continue-on-error`.
+#[derive(Debug)]
+pub struct FilesFailed(pub u64);
+
+impl std::fmt::Display for FilesFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} file(s) could not be sent", self.0)
+    }
+}
+
+impl std::error::Error for FilesFailed {}
+
+/// A connected session with the server. Every socket operation goes through
+/// here so timeouts apply everywhere and I/O failures become `ConnectionLost`.
+struct Connection {
+    socket: TcpStream,
+    timeouts: Timeouts,
+    heartbeat: Duration,
+    last_activity: Instant,
+    throttle: Throttle,
+    /// Cleared for the rest of the session once `sendfile` turns out not to work
+    zero_copy: bool,
+    buffers: usize,
+    /// The server sends `Stored` notices, so sent files wait for one
+    acks: bool,
+    /// Files whose data went out but that the server hasn't confirmed, oldest first
+    unacked: VecDeque<i64>,
+    /// Files the server confirmed, still to be marked sent in the log
+    stored: Vec<i64>,
+}
+
+impl Connection {
+    async fn open(addr: &str, options: &SendOptions, pending_bytes: u64) -> Result<Self> {
+        let socket = net::timed(options.timeouts.io, TcpStream::connect(addr))
+            .await
+            .map_err(ConnectionLost)?;
+        net::configure_socket(&socket, &options.timeouts).map_err(ConnectionLost)?;
+        let mut conn = Connection {
+            socket,
+            timeouts: options.timeouts,
+            heartbeat: options.heartbeat,
+            last_activity: Instant::now(),
+            throttle: Throttle::new(options.rate_limit, options.schedule.clone()),
+            zero_copy: options.zero_copy,
+            buffers: options.buffers.max(1),
+            acks: false,
+            unacked: VecDeque::new(),
+            stored: Vec::new(),
+        };
+
+        let hello = Hello {
+            conflict: options.conflict,
+            session: Some(options.session.clone()),
+            total_bytes: Some(pending_bytes),
+            acks: true,
+        };
+        match conn.request(&ClientMessage::Hello(hello)).await? {
+            ServerResponse::Welcome {
+                conflict,
+                warning,
+                acks,
+            } => {
+                // Older servers don't acknowledge anything; files count as
+                // sent once their data is out, as before
+                conn.acks = acks;
+                if options.conflict.is_some_and(|wanted| wanted != conflict) {
+                    println!("Server enforces conflict policy: {}", conflict);
+                }
+                if let Some(warning) = warning {
+                    eprintln!("Warning: {}", warning);
+                }
+            }
+            other => return Err(anyhow!("Unexpected reply to hello: {:?}", other)),
+        }
+        Ok(conn)
+    }
+
+    async fn request(&mut self, msg: &ClientMessage) -> Result<ServerResponse> {
+        net::timed(self.timeouts.io, codec::write_client(&mut self.socket, msg))
+            .await
+            .map_err(connection_error)?;
+        self.last_activity = Instant::now();
+
+        // Wait for response. Conflict and stored notices come first.
+        let resp = loop {
+            let resp = net::timed(self.timeouts.io, codec::read_server(&mut self.socket))
+                .await
+                .map_err(connection_error)?;
+            match resp {
+                ServerResponse::Conflict { message } => println!("\n{}", message),
+                ServerResponse::Stored { files } => self.on_stored(files),
+                resp => break resp,
+            }
+        };
+        match resp {
+            ServerResponse::Rejected { message, retry } if retry => {
+                Err(ConnectionLost(std::io::Error::other(message)).into())
+            }
+            ServerResponse::Rejected { message, .. } => {
+                Err(anyhow!("Server refused the connection: {}", message))
+            }
+            ServerResponse::Error {
+                message,
+                category: ErrorCategory::OutOfSpace,
+                relative_path,
+            } => Err(OutOfSpace {
+                message,
+                relative_path,
+            }
+            .into()),
+            resp => Ok(resp),
+        }
+    }
+
+    async fn write_data(&mut self, buf: &[u8]) -> Result<()> {
+        if let Err(e) = net::timed(self.timeouts.io, self.socket.write_all(buf)).await {
+            return Err(self.explain_write_error(e).await);
+        }
+        self.throttle_after(buf.len()).await;
+        Ok(())
+    }
+
+    /// Sends up to `len` bytes of `file` from `*offset` without copying them
+    /// through user space, advancing `*offset`. `Ok(None)` means `sendfile`
+    /// can't be used here and nothing was sent.
+    async fn write_file_range(
+        &mut self,
+        file: &File,
+        offset: &mut u64,
+        len: usize,
+    ) -> Result<Option<usize>> {
+        let sent = net::timed(
+            self.timeouts.io,
+            zerocopy::send_file(&self.socket, file, offset, len),
+        )
+        .await;
+        match sent {
+            Ok(0) => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => {
+                self.throttle_after(n).await;
+                Ok(Some(n))
+            }
+            Err(e) if zerocopy::is_unsupported(&e) => {
+                self.zero_copy = false;
+                Ok(None)
+            }
+            Err(e) if zerocopy::is_file_error(&e) => Err(e.into()),
+            Err(e) => Err(self.explain_write_error(e).await),
+        }
+    }
+
+    async fn throttle_after(&mut self, bytes: usize) {
+        self.last_activity = Instant::now();
+        let delay = self.throttle.reserve(bytes as u64);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// A server that gives up mid-file says why before closing; pick that
+    /// up if it is there rather than reporting a bare broken pipe.
+    async fn explain_write_error(&mut self, e: std::io::Error) -> anyhow::Error {
+        let read = async {
+            loop {
+                match codec::read_server(&mut self.socket).await {
+                    Ok(ServerResponse::Stored { files }) => self.on_stored(files),
+                    other => break other,
+                }
+            }
+        };
+        let reason = tokio::time::timeout(Duration::from_secs(2), read).await;
+        match reason {
+            Ok(Ok(ServerResponse::Error {
+                message,
+                category: ErrorCategory::OutOfSpace,
+                relative_path,
+            })) => OutOfSpace {
+                message,
+                relative_path,
+            }
+            .into(),
+            _ => ConnectionLost(e).into(),
+        }
+    }
+
+    fn on_stored(&mut self, files: u64) {
+        let files = (files as usize).min(self.unacked.len());
+        self.stored.extend(self.unacked.drain(..files));
+    }
+
+    /// Records that all of a file's data went out. It only counts as sent
+    /// once the server says it is stored, so a server crash before then
+    /// can't lose it; a dropped session leaves it Pending to be offered again.
+    fn data_sent(&mut self, log: &TransferLog, id: i64) -> Result<()> {
+        if self.acks {
+            self.unacked.push_back(id);
+        } else {
+            log.mark_sent(id)?;
+        }
+        Ok(())
+    }
+
+    /// Marks the files the server has confirmed as sent.
+    fn settle(&mut self, log: &TransferLog) -> Result<()> {
+        for id in self.stored.drain(..) {
+            log.mark_sent(id)?;
+        }
+        Ok(())
+    }
+
+    /// Pings the server if nothing was sent for a while (e.g. while working
+    /// through a long run of excluded files), so its idle timeout doesn't
+    /// fire and a dead link is noticed here rather than on the next file.
+    async fn heartbeat_if_idle(&mut self) -> Result<()> {
+        if self.heartbeat.is_zero() || self.last_activity.elapsed() < self.heartbeat {
+            return Ok(());
+        }
+        match self.request(&ClientMessage::Ping).await? {
+            ServerResponse::Pong => Ok(()),
+            other => Err(anyhow!("Unexpected reply to ping: {:?}", other)),
+        }
+    }
+
+    /// Waits `duration`, pinging the server as needed so it doesn't drop
+    /// the session meanwhile. Stops early if we are shutting down.
+    async fn idle(&mut self, duration: Duration, shutdown: &Shutdown) -> Result<()> {
+        let until = Instant::now() + duration;
+        loop {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(());
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(left.min(SCAN_POLL)) => {}
+                _ = shutdown.wait() => {}
+            }
+            shutdown.check()?;
+            self.heartbeat_if_idle().await?;
+        }
+    }
+}
+
+/// Progress counters and the status line, kept across reconnects so the
+/// numbers don't jump back when a new session starts.
+struct Progress {
+    total_files_count: u64,
+    // processed_files includes Sent and Skipped files (basically anything NOT Pending initially)
+    processed_files: u64,
+    total_skipped: u64,
+    failed: u64,
+    total_bytes_sent_from_log: u64, // Total bytes sent from previous runs
+    session_bytes_sent: u64,        // Bytes sent in this run
+    // We need total pending size for ETA
+    total_pending_size: u64,
+    start_time: Instant,
+    last_update: Instant,
+    update_interval: Duration,
+}
+
+impl Progress {
+    fn new(log: &TransferLog) -> Result<Self> {
+        let total_files_count = log.count_total()?;
+        let total_pending_size = log.pending_bytes()?;
+        Ok(Progress {
+            total_files_count,
+            processed_files: total_files_count - log.count_pending()?,
+            total_skipped: log.count_skipped()?,
+            failed: 0,
+            total_bytes_sent_from_log: log.get_total_sent_bytes()?,
+            session_bytes_sent: 0,
+            total_pending_size,
+            start_time: Instant::now(),
+            last_update: Instant::now(),
+            update_interval: Duration::from_millis(300),
+        })
+    }
+
+    fn percent(&self) -> f64 {
+        if self.total_files_count > 0 {
+            (self.processed_files as f64 / self.total_files_count as f64) * 100.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Redraws the status line if the update interval has passed.
+    fn tick(&mut self, current: &str) -> Result<()> {
+        if self.last_update.elapsed() >= self.update_interval {
+            self.render(current)?;
+            self.last_update = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn render(&self, current: &str) -> Result<()> {
+        let elapsed = self.start_time.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.session_bytes_sent as f64 / elapsed
+        } else {
+            0.0
+        };
+        let remaining_bytes = self
+            .total_pending_size
+            .saturating_sub(self.session_bytes_sent);
+        let eta_seconds = if rate > 0.0 {
+            remaining_bytes as f64 / rate
+        } else {
+            0.0
+        };
+
+        let eta_str = if eta_seconds > 3600.0 {
+            format!(
+                "{:.0}h {:.0}m",
+                eta_seconds / 3600.0,
+                (eta_seconds % 3600.0) / 60.0
+            )
+        } else if eta_seconds > 60.0 {
+            format!("{:.0}m {:.0}s", eta_seconds / 60.0, eta_seconds % 60.0)
+        } else {
+            format!("{:.0}s", eta_seconds)
+        };
+
+        print!(
+            "\rSending: [{:.1}%] Files: {}/{}, Skipped: {}, Size: {} | ETA: {} | Current: {:.30}               ",
+            self.percent(),
+            self.processed_files,
+            self.total_files_count,
+            self.total_skipped,
+            format_size(self.total_bytes_sent_from_log + self.session_bytes_sent),
+            eta_str,
+            current
+        );
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+
+    fn add_bytes(&mut self, n: u64) {
+        self.session_bytes_sent += n;
+    }
+
+    fn skip(&mut self, size: u64) {
+        self.total_skipped += 1;
+        self.processed_files += 1; // Count as processed
+        self.total_pending_size = self.total_pending_size.saturating_sub(size);
+    }
+
+    /// Takes in what a scan running alongside has added or requeued since.
+    fn refresh(&mut self, log: &TransferLog) -> Result<()> {
+        self.total_files_count = log.count_total()?;
+        self.processed_files = self.total_files_count - log.count_pending()?;
+        self.total_skipped = log.count_skipped()?;
+        self.total_pending_size = self.session_bytes_sent + log.pending_bytes()?;
+        Ok(())
+    }
+}
+
+/// How often a sender that is ahead of the scan checks for new files.
+const SCAN_POLL: Duration = Duration::from_millis(500);
+
+/// Sends everything pending in `log`. The scan may still be adding files;
+/// `listing_complete` says when it is done, and until then running out of
+/// pending files means waiting for more rather than finishing.
+#[allow(clippy::too_many_arguments)]
 pub async fn send_pending_files(
     source_path: PathBuf,
     ip: String,
     port: u16,
     log: &TransferLog,
     exclude_patterns: &[String],
+    found_excludes: &[String],
+    options: &SendOptions,
+    listing_complete: &dyn Fn() -> Result<bool>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    // IPv6 literals need brackets before the port
+    let addr = if ip.contains(':') && !ip.starts_with('[') {
+        format!("[{}]:{}", ip, port)
+    } else {
+        format!("{}:{}", ip, port)
+    };
+
+    // Compile patterns for filtering, with what the scan read from ignore files
+    let mut rules = IgnoreRules::new(exclude_patterns);
+    rules.add_found(found_excludes);
+
+    if options.rate_limit > 0 {
+        println!("Rate limit: {}/s", format_size(options.rate_limit));
+    }
+    if let Some(schedule) = &options.schedule {
+        println!("Rate schedule: {}", schedule);
+    }
+
+    let mut progress = Progress::new(log)?;
+    let mut attempt = 0;
+
+    loop {
+        let processed_before = progress.processed_files;
+        match send_session(
+            &addr,
+            &source_path,
+            log,
+            &rules,
+            options,
+            listing_complete,
+            shutdown,
+            &mut progress,
+        )
+        .await
+        {
+            Ok(()) => break,
+            Err(e) if e.is::<StreamAborted>() => continue,
+            Err(e) if e.is::<ConnectionLost>() => {
+                // A session that got files through earns a fresh retry budget,
+                // so a flaky link only gives up when it stops making progress.
+                if progress.processed_files > processed_before {
+                    attempt = 0;
+                }
+                if attempt >= options.retries {
+                    return Err(e);
+                }
+                let delay = options
+                    .retry_delay
+                    .saturating_mul(2u32.saturating_pow(attempt))
+                    .min(options.max_retry_delay);
+                attempt += 1;
+                eprintln!(
+                    "\n{}. Reconnecting in {}s (attempt {}/{})...",
+                    e,
+                    delay.as_secs_f64(),
+                    attempt,
+                    options.retries
+                );
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown.wait() => {}
+                }
+                shutdown.check()?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Final update
+    println!(
+        "\rDone! Total Files: {}, Skipped: {}, Total Size: {}                                    ",
+        progress.processed_files,
+        progress.total_skipped,
+        format_size(progress.total_bytes_sent_from_log + progress.session_bytes_sent)
+    );
+
+    if progress.failed > 0 {
+        eprintln!("Failed files:");
+        for (relative_path, error) in log.get_failed_files()? {
+            eprintln!("  {}: {}", relative_path, error);
+        }
+        return Err(FilesFailed(progress.failed).into());
+    }
+    Ok(())
+}
+
+/// Runs one connection's worth of the transfer. Pending files are re-read
+/// from the log each time, so after a reconnect finished files are not
+/// offered again and the interrupted one continues through `Resume`.
+#[allow(clippy::too_many_arguments)]
+async fn send_session(
+    addr: &str,
+    source_path: &Path,
+    log: &TransferLog,
+    rules: &IgnoreRules,
+    options: &SendOptions,
+    listing_complete: &dyn Fn() -> Result<bool>,
+    shutdown: &Shutdown,
+    progress: &mut Progress,
 ) -> Result<()> {
     // Connect to server
-    let addr = format!("{}:{}", ip, port);
     println!("Connecting to {}...", addr);
-    let mut socket = TcpStream::connect(&addr).await?;
-    socket.set_nodelay(true)?; // Disable Nagle's algorithm for lower latency
+    let mut conn = Connection::open(addr, options, progress.total_pending_size).await?;
     println!("Connected.");
 
-    let pending_files = log.get_pending_files()?;
-    if pending_files.is_empty() {
+    let mut scanning = !listing_complete()?;
+    // The scan may have moved on since the counts were taken
+    progress.refresh(log)?;
+    if !scanning && log.count_pending()? == 0 {
         println!("No pending files to send.");
         return Ok(());
     }
 
-    // Compile patterns for filtering
-    let patterns: Vec<Pattern> = exclude_patterns
-        .iter()
-        .filter_map(|p| Pattern::new(p).ok())
-        .collect();
-
-    // Stats
-    let total_files_count = log.count_total()?;
-    // processed_files includes Sent and Skipped files (basically anything NOT Pending initially)
-    let mut processed_files = total_files_count - log.count_pending()?;
-    let mut total_skipped = log.count_skipped()?;
-
-    let total_bytes_sent_from_log = log.get_total_sent_bytes()?; // Total bytes sent from previous sessions
-    let mut current_total_bytes_sent = 0u64; // Bytes sent in this session
-    let mut last_update = Instant::now();
-    let start_time = Instant::now();
-    let update_interval = Duration::from_millis(300);
-
-    // We need total pending size for ETA
-    let mut total_pending_size: u64 = pending_files.iter().map(|f| f.size).sum();
-    let mut session_bytes_sent = 0u64;
-
     // Initial status
-    let initial_percent = if total_files_count > 0 {
-        (processed_files as f64 / total_files_count as f64) * 100.0
-    } else {
-        0.0
-    };
-
     print!(
         "\rSending: [{:.1}%] Files: {}/{}, Skipped: {}, Size: 0 B, ETA: --:--",
-        initial_percent, processed_files, total_files_count, total_skipped
+        progress.percent(),
+        progress.processed_files,
+        progress.total_files_count,
+        progress.total_skipped
     );
     std::io::stdout().flush()?;
 
-    for record in pending_files {
+    // Files that changed since the scan go to the back of the queue once,
+    // giving whatever is writing them time to finish.
+    let mut pending = log.pending_files();
+    let mut requeue: VecDeque<FileRecord> = VecDeque::new();
+    let mut last_sent: Option<i64> = None;
+    let mut last_poll = Instant::now();
+
+    loop {
+        conn.settle(log)?;
+        if scanning && last_poll.elapsed() >= SCAN_POLL {
+            scanning = !listing_complete()?;
+            progress.refresh(log)?;
+            last_poll = Instant::now();
+            // A rescan may have put files the cursor already passed back to
+            // Pending; one more pass from the start picks them up
+            if !scanning {
+                pending = log.pending_files();
+            }
+        }
+        let (record, requeued) = match pending.next() {
+            Some(record) => {
+                let record = record?;
+                // Still waiting its turn in the requeue, or already sent and
+                // waiting for the server to confirm it
+                if requeue.iter().any(|r| r.id == record.id) || conn.unacked.contains(&record.id) {
+                    continue;
+                }
+                (record, false)
+            }
+            None => match requeue.pop_front() {
+                Some(record) => (record, true),
+                // Caught up with the scan; the cursor picks up whatever it
+                // adds next, and once it is done one more pass finds the rest
+                None if scanning => {
+                    conn.idle(SCAN_POLL, shutdown).await?;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        shutdown.check()?;
+
         // Construct absolute path
         let root = source_path.parent().unwrap_or(Path::new("."));
         let file_path = root.join(&record.relative_path);
 
+        conn.heartbeat_if_idle().await?;
+
         // Check if excluded
-        if patterns.iter().any(|p| p.matches(&record.relative_path)) {
-            log.mark_skipped(&record.relative_path)?;
-            total_skipped += 1;
-            processed_files += 1; // Count as processed
-            total_pending_size = total_pending_size.saturating_sub(record.size);
+        // Patterns may have changed since the scan, so look at the parent
+        // directories too
+        if rules.excludes_with_parents(rule_path(&record.relative_path), record.is_dir) {
+            log.mark_skipped(record.id)?;
+            progress.skip(record.size);
             continue;
         }
 
         if !file_path.exists() {
             eprintln!("\nWarning: File not found: {:?}, skipping.", file_path);
-            processed_files += 1; // Count as processed (failed/skipped)
-            // Should we mark as skipped in DB? Maybe better to skip implicitly or mark skipped.
-            // If we don't mark, it stays pending. Let's mark skipped to avoid infinite loop on restart.
-            // But logic "Should we mark as failed?" suggests maybe not.
+            // Mark skipped to avoid infinite loop on restart.
             // For progress bar consistency, we count it.
-            total_pending_size = total_pending_size.saturating_sub(record.size);
-            log.mark_skipped(&record.relative_path)?; // Mark skipped to be safe
+            log.mark_skipped(record.id)?;
+            progress.processed_files += 1; // Count as processed (failed/skipped)
+            progress.total_pending_size = progress.total_pending_size.saturating_sub(record.size);
             continue;
         }
 
+        let id = record.id;
         let is_dir = record.is_dir;
-        let size = record.size;
+        let mut size = record.size;
         let relative_path_clean = record.relative_path;
 
+        // Open the file before offering it, so an unreadable or changed file
+        // is dealt with while the connection is still between files.
+        let file = if is_dir {
+            None
+        } else {
+            let opened = open_for_send(
+                &file_path, &mut size, options, requeued, &mut conn, shutdown,
+            )
+            .await;
+            if size != record.size {
+                log.update_size(id, size)?;
+                progress.total_pending_size =
+                    (progress.total_pending_size + size).saturating_sub(record.size);
+            }
+            match opened {
+                Ok(Some(file)) => Some(file),
+                Ok(None) => {
+                    let record = FileRecord {
+                        relative_path: relative_path_clean,
+                        size,
+                        ..record
+                    };
+                    requeue.push_back(record);
+                    continue;
+                }
+                // Lost the connection or stopping while the file settled
+                Err(e) if e.is::<ConnectionLost>() || e.is::<Interrupted>() => return Err(e),
+                Err(e) => {
+                    fail_file(log, progress, options, id, &relative_path_clean, size, e)?;
+                    continue;
+                }
+            }
+        };
+
+        let mtime = match &file {
+            Some(file) => file.metadata().await.ok().as_ref().and_then(unix_mtime),
+            None => None,
+        };
         let meta = FileMetadata {
             relative_path: relative_path_clean.clone(),
             size,
             is_dir,
+            mtime,
         };
 
-        // Update UI loop (Inner loop for large files or just pre-send update)
-        // We reuse the update logic block
-        let update_ui = |current_processed: u64,
-                         current_skipped: u64,
-                         session_bytes: u64,
-                         total_sent: u64|
-         -> Result<()> {
-            let elapsed = start_time.elapsed().as_secs_f64();
-            let rate = if elapsed > 0.0 {
-                session_bytes as f64 / elapsed
-            } else {
-                0.0
-            };
-            let remaining_bytes = total_pending_size.saturating_sub(session_bytes);
-            let eta_seconds = if rate > 0.0 {
-                remaining_bytes as f64 / rate
-            } else {
-                0.0
-            };
-
-            let eta_str = if eta_seconds > 3600.0 {
-                format!(
-                    "{:.0}h {:.0}m",
-                    eta_seconds / 3600.0,
-                    (eta_seconds % 3600.0) / 60.0
-                )
-            } else if eta_seconds > 60.0 {
-                format!("{:.0}m {:.0}s", eta_seconds / 60.0, eta_seconds % 60.0)
-            } else {
-                format!("{:.0}s", eta_seconds)
-            };
-
-            let percent = if total_files_count > 0 {
-                (current_processed as f64 / total_files_count as f64) * 100.0
-            } else {
-                0.0
-            };
-
-            print!(
-                "\rSending: [{:.1}%] Files: {}/{}, Skipped: {}, Size: {} | ETA: {} | Current: {:.30}               ",
-                percent,
-                current_processed,
-                total_files_count,
-                current_skipped,
-                format_size(total_sent),
-                eta_str,
-                relative_path_clean
-            );
-            std::io::stdout().flush()?;
-            Ok(())
-        };
-
-        if last_update.elapsed() >= update_interval {
-            update_ui(
-                processed_files,
-                total_skipped,
-                session_bytes_sent,
-                total_bytes_sent_from_log + current_total_bytes_sent,
-            )?;
-            last_update = Instant::now();
-        }
-
-        // Send metadata
-        let json = serde_json::to_vec(&meta)?;
-        let len = (json.len() as u32).to_be_bytes();
-        socket.write_all(&len).await?;
-        socket.write_all(&json).await?;
-
-        // Wait for response
-        let mut len_buf = [0u8; 4];
-        if socket.read_exact(&mut len_buf).await.is_err() {
-            return Err(anyhow!("Connection closed by server"));
-        }
-        let len = u32::from_be_bytes(len_buf) as usize;
-        let mut resp_buf = vec![0u8; len];
-        socket.read_exact(&mut resp_buf).await?;
+        progress.tick(&relative_path_clean)?;
 
-        let response: ServerResponse = serde_json::from_slice(&resp_buf)?;
+        // Send metadata and wait for response
+        let response = match conn.request(&ClientMessage::File(meta)).await {
+            Err(e) if e.is::<OutOfSpace>() => {
+                // Either this file doesn't fit or the previous one didn't
+                let offered = Some((id, relative_path_clean.as_str()));
+                return Err(blame_out_of_space(log, offered, last_sent, e)?);
+            }
+            response => response?,
+        };
 
-        match response {
+        let offset = match response {
             ServerResponse::Skip => {
                 if !is_dir {
-                    log.mark_skipped(&relative_path_clean)?;
-                    total_skipped += 1;
-                    total_pending_size = total_pending_size.saturating_sub(size);
+                    log.mark_skipped(id)?;
+                    progress.skip(size);
                 } else {
-                    log.mark_sent(&relative_path_clean)?;
+                    log.mark_sent(id)?;
+                    progress.processed_files += 1;
                 }
-                processed_files += 1;
+                continue;
             }
-            ServerResponse::Send => {
-                if !is_dir {
-                    let mut file = File::open(&file_path).await?;
-                    let current_size = file.metadata().await?.len();
-                    if current_size != size {
-                        return Err(anyhow!("File changed: {}", relative_path_clean));
-                    }
-
-                    // Custom copy loop with progress
-                    // Increased buffer size to 1MB
-                    let mut buf = vec![0u8; 1024 * 1024];
-                    let mut remaining = size;
-                    let mut file_sent = 0;
-
-                    loop {
-                        let to_read = std::cmp::min(buf.len() as u64, remaining) as usize;
-                        if to_read == 0 {
-                            break;
-                        }
-
-                        let n = file.read_exact(&mut buf[..to_read]).await?;
-
-                        socket.write_all(&buf[..n]).await?;
-
-                        remaining -= n as u64;
-                        current_total_bytes_sent += n as u64;
-                        session_bytes_sent += n as u64;
-                        file_sent += n as u64;
-
-                        if last_update.elapsed() >= update_interval {
-                            update_ui(
-                                processed_files,
-                                total_skipped,
-                                session_bytes_sent,
-                                total_bytes_sent_from_log + current_total_bytes_sent,
-                            )?;
-                            last_update = Instant::now();
-                        }
-                    }
-
-                    if file_sent != size {
-                        return Err(anyhow!("Incomplete transfer: {}", relative_path_clean));
-                    }
-
-                    processed_files += 1;
-                    log.mark_sent(&relative_path_clean)?;
-                } else {
-                    log.mark_sent(&relative_path_clean)?;
-                }
+            ServerResponse::Send => 0,
+            ServerResponse::Resume { offset } => offset,
+            ServerResponse::Error { message, .. } => {
+                let e = anyhow!("Server error: {}", message);
+                fail_file(log, progress, options, id, &relative_path_clean, size, e)?;
+                continue;
             }
-            ServerResponse::Resume { offset } => {
-                if !is_dir {
-                    let mut file = File::open(&file_path).await?;
-                    file.seek(tokio::io::SeekFrom::Start(offset)).await?;
-
-                    // Increased buffer size to 1MB
-                    let mut buf = vec![0u8; 1024 * 1024];
-                    let mut remaining = size - offset; // Send remainder
-
-                    loop {
-                        let to_read = std::cmp::min(buf.len() as u64, remaining) as usize;
-                        if to_read == 0 {
-                            break;
-                        }
-
-                        let n = file.read(&mut buf[..to_read]).await?;
-                        if n == 0 {
-                            break;
-                        } // EOF
-
-                        socket.write_all(&buf[..n]).await?;
-
-                        remaining -= n as u64;
-                        current_total_bytes_sent += n as u64;
-                        session_bytes_sent += n as u64;
-
-                        if last_update.elapsed() >= update_interval {
-                            update_ui(
-                                processed_files,
-                                total_skipped,
-                                session_bytes_sent,
-                                total_bytes_sent_from_log + current_total_bytes_sent,
-                            )?;
-                            last_update = Instant::now();
-                        }
-                    }
-
-                    processed_files += 1;
-                    log.mark_sent(&relative_path_clean)?;
-                } else {
-                    log.mark_sent(&relative_path_clean)?;
+            other => {
+                return Err(anyhow!(
+                    "Unexpected reply for {}: {:?}",
+                    relative_path_clean,
+                    other
+                ));
+            }
+        };
+
+        let Some(mut file) = file else {
+            log.mark_sent(id)?;
+            continue;
+        };
+
+        match send_file_data(
+            &mut conn,
+            &mut file,
+            offset,
+            size,
+            progress,
+            shutdown,
+            &relative_path_clean,
+        )
+        .await
+        {
+            Ok(()) => {
+                progress.processed_files += 1;
+                conn.data_sent(log, id)?;
+                last_sent = Some(id);
+            }
+            Err(e) if e.is::<OutOfSpace>() => {
+                // Marked failed so `resume` offers it again once there's room
+                log.mark_failed(id, &e.to_string())?;
+                return Err(e);
+            }
+            Err(e) if e.is::<ConnectionLost>() || e.is::<Interrupted>() => return Err(e),
+            Err(e) => {
+                // The server is still waiting for the rest of the data, so
+                // this connection can't carry on with the next file.
+                fail_file(log, progress, options, id, &relative_path_clean, size, e)?;
+                return Err(StreamAborted.into());
+            }
+        }
+    }
+
+    // Check in once more: the server stores what it still holds before
+    // answering the ping, or says it failed to store the last file.
+    if last_sent.is_some() {
+        match conn.request(&ClientMessage::Ping).await {
+            Ok(ServerResponse::Pong) => {
+                conn.settle(log)?;
+                if !conn.unacked.is_empty() {
+                    return Err(anyhow!(
+                        "Server didn't confirm storing {} files; they stay pending",
+                        conn.unacked.len()
+                    ));
                 }
             }
-            ServerResponse::Error { message } => {
-                return Err(anyhow!("Server error: {}", message));
+            Ok(other) => return Err(anyhow!("Unexpected reply to ping: {:?}", other)),
+            Err(e) if e.is::<OutOfSpace>() => {
+                return Err(blame_out_of_space(log, None, last_sent, e)?);
             }
+            Err(e) => return Err(e),
         }
     }
 
-    // Final update
-    println!(
-        "\rDone! Total Files: {}, Skipped: {}, Total Size: {}                                    ",
-        processed_files,
-        total_skipped,
-        format_size(total_bytes_sent_from_log + current_total_bytes_sent)
-    );
+    Ok(())
+}
+
+/// The server can refuse the file being offered for lack of space, or run
+/// out while storing the previous one after we handed it all the data and
+/// only say so on the next request. Marks the file the error names failed
+/// so `resume` sends it again (both, for a server that doesn't name one),
+/// and passes the error on.
+fn blame_out_of_space(
+    log: &TransferLog,
+    offered: Option<(i64, &str)>,
+    last_sent: Option<i64>,
+    e: anyhow::Error,
+) -> Result<anyhow::Error> {
+    let named = e
+        .downcast_ref::<OutOfSpace>()
+        .and_then(|e| e.relative_path.as_deref());
+    let is_offered = offered.is_some_and(|(_, path)| named == Some(path));
+    if let Some((id, _)) = offered
+        && (named.is_none() || is_offered)
+    {
+        log.mark_failed(id, &e.to_string())?;
+    }
+    if let Some(id) = last_sent
+        && !is_offered
+    {
+        log.mark_failed(id, &e.to_string())?;
+    }
+    Ok(e)
+}
+
+/// Opens a file that is about to be sent. `size` is updated when the file
+/// changed since the scan. Returns `None` when the file should go to the end
+/// of the queue instead of being sent now.
+async fn open_for_send(
+    path: &Path,
+    size: &mut u64,
+    options: &SendOptions,
+    requeued: bool,
+    conn: &mut Connection,
+    shutdown: &Shutdown,
+) -> Result<Option<File>> {
+    let file = File::open(path).await?;
+    let meta = file.metadata().await?;
+
+    if meta.len() != *size {
+        if options.on_change == ChangePolicy::Fail {
+            return Err(anyhow!(
+                "File changed (scanned {} bytes, now {} bytes)",
+                size,
+                meta.len()
+            ));
+        }
+        *size = meta.len();
+        if options.on_change == ChangePolicy::Requeue && !requeued {
+            return Ok(None);
+        }
+    }
+
+    let stable_for = options.stable_for;
+    if !stable_for.is_zero() && modified_within(&meta, stable_for) {
+        if !requeued {
+            return Ok(None);
+        }
+        // Still busy on the second pass: give it one more interval to settle,
+        // then send whatever is there.
+        conn.idle(stable_for, shutdown).await?;
+        *size = file.metadata().await?.len();
+    }
+
+    Ok(Some(file))
+}
+
+fn modified_within(meta: &std::fs::Metadata, interval: Duration) -> bool {
+    meta.modified()
+        .ok()
+        .and_then(|mtime| mtime.elapsed().ok())
+        .is_some_and(|age| age < interval)
+}
+
+/// Size of each read-ahead buffer.
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// Bytes handed to one `sendfile` call; same as a read-ahead buffer.
+const ZERO_COPY_CHUNK: usize = READ_CHUNK;
+
+/// Streams `size - offset` bytes of `file` to the server.
+async fn send_file_data(
+    conn: &mut Connection,
+    file: &mut File,
+    offset: u64,
+    size: u64,
+    progress: &mut Progress,
+    shutdown: &Shutdown,
+    relative_path: &str,
+) -> Result<()> {
+    let mut position = offset;
+    while conn.zero_copy && position < size {
+        let chunk = conn.throttle.chunk_size(ZERO_COPY_CHUNK);
+        let len = std::cmp::min(chunk as u64, size - position) as usize;
+        let Some(n) = conn.write_file_range(file, &mut position, len).await? else {
+            break; // nothing sent; carry on below with the buffered loop
+        };
+        shutdown.check()?;
+        progress.add_bytes(n as u64);
+        progress.tick(relative_path)?;
+    }
+    if position == size {
+        return Ok(());
+    }
+    if position > 0 {
+        file.seek(tokio::io::SeekFrom::Start(position)).await?;
+    }
+
+    // The file is read ahead into up to `buffers` buffers while earlier ones
+    // are written to the socket, so the disk and the network both stay busy
+    let (filled_tx, mut filled_rx) = mpsc::channel(conn.buffers);
+    let (free_tx, free_rx) = mpsc::channel(conn.buffers);
+    let reading = read_ahead(file, size - position, conn.buffers, filled_tx, free_rx);
+    // Owns its channel ends, so returning early (an error, Ctrl-C) closes
+    // them and `read_ahead` stops instead of waiting on them forever
+    let sending = async move {
+        while let Some(filled) = filled_rx.recv().await {
+            let (buf, len) = filled?;
+            let mut sent = 0;
+            while sent < len {
+                // Smaller writes when throttled keep the pauses between them short
+                let end = sent + conn.throttle.chunk_size(len - sent);
+                conn.write_data(&buf[sent..end]).await?;
+                // Stopping here leaves a partial on the server that
+                // the next run picks up through `Resume`.
+                shutdown.check()?;
+
+                progress.add_bytes((end - sent) as u64);
+                progress.tick(relative_path)?;
+                sent = end;
+            }
+            let _ = free_tx.send(buf).await;
+        }
+        Ok(())
+    };
+    tokio::join!(reading, sending).1
+}
+
+/// Reads the next `remaining` bytes of `file` into buffers for the socket
+/// side of `send_file_data`, allocating at most `buffers` of them and then
+/// reusing the ones it hands back. Stops at the first read error, which is
+/// passed along, or when the socket side gives up.
+async fn read_ahead(
+    file: &mut File,
+    mut remaining: u64,
+    buffers: usize,
+    filled: mpsc::Sender<std::io::Result<(Vec<u8>, usize)>>,
+    mut free: mpsc::Receiver<Vec<u8>>,
+) {
+    let mut allocated = 0;
+    while remaining > 0 {
+        let mut buf = if allocated < buffers {
+            allocated += 1;
+            // Small files don't need a full-size buffer
+            vec![0u8; remaining.min(READ_CHUNK as u64) as usize]
+        } else {
+            match free.recv().await {
+                Some(buf) => buf,
+                None => return,
+            }
+        };
+        let len = remaining.min(buf.len() as u64) as usize;
+        let read = file.read_exact(&mut buf[..len]).await.map(|_| (buf, len));
+        let failed = read.is_err();
+        if filled.send(read).await.is_err() || failed {
+            return;
+        }
+        remaining -= len as u64;
+    }
+}
+
+/// Records a file that could not be sent. Without `--continue-on-error` the
+/// error is passed on and ends the transfer.
+fn fail_file(
+    log: &TransferLog,
+    progress: &mut Progress,
+    options: &SendOptions,
+    id: i64,
+    relative_path: &str,
+    size: u64,
+    error: anyhow::Error,
+) -> Result<()> {
+    log.mark_failed(id, &error.to_string())?;
+    if !options.continue_on_error {
+        return Err(anyhow!("Failed to send {}: {}", relative_path, error));
+    }
+    eprintln!("\nFailed: {}: {}", relative_path, error);
+    progress.failed += 1;
+    progress.processed_files += 1;
+    progress.total_pending_size = progress.total_pending_size.saturating_sub(size);
     Ok(())
 }
 
@@ -0,0 +1,302 @@
+//! Wire framing. Every message is `[type: u8][length: u32 BE][payload]`, where
+//! the payload is JSON (or empty for pings). Lengths are checked before
+//! anything is allocated, so a bad peer can't make us reserve gigabytes.
+
+use crate::protocol::{ClientMessage, FileMetadata, Hello, ServerResponse};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest payload either side will accept. Metadata frames carry a path and
+/// a few numbers, so this leaves plenty of room.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+const TAG_FILE: u8 = 0x01;
+const TAG_PING: u8 = 0x02;
+const TAG_HELLO: u8 = 0x03;
+const TAG_RESPONSE: u8 = 0x81;
+const TAG_PONG: u8 = 0x82;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    Oversized { len: u32 },
+    UnknownType(u8),
+    Malformed(String),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Oversized { len } => write!(
+                f,
+                "Frame of {} bytes exceeds the {} byte limit",
+                len, MAX_FRAME_LEN
+            ),
+            FrameError::UnknownType(tag) => write!(f, "Unknown message type 0x{:02x}", tag),
+            FrameError::Malformed(reason) => write!(f, "Malformed message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(e: io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+pub async fn write_client<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &ClientMessage,
+) -> Result<(), FrameError> {
+    let frame = match msg {
+        ClientMessage::File(meta) => encode(TAG_FILE, &to_json(meta)?),
+        ClientMessage::Ping => encode(TAG_PING, &[]),
+        ClientMessage::Hello(hello) => encode(TAG_HELLO, &to_json(hello)?),
+    };
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+pub async fn read_client<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<ClientMessage, FrameError> {
+    let (tag, payload) = read_frame(reader).await?;
+    decode_client(tag, &payload)
+}
+
+pub async fn write_server<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    resp: &ServerResponse,
+) -> Result<(), FrameError> {
+    let frame = match resp {
+        ServerResponse::Pong => encode(TAG_PONG, &[]),
+        other => encode(TAG_RESPONSE, &to_json(other)?),
+    };
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+pub async fn read_server<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<ServerResponse, FrameError> {
+    let (tag, payload) = read_frame(reader).await?;
+    decode_server(tag, &payload)
+}
+
+pub fn decode_client(tag: u8, payload: &[u8]) -> Result<ClientMessage, FrameError> {
+    match tag {
+        TAG_FILE => Ok(ClientMessage::File(from_json::<FileMetadata>(payload)?)),
+        TAG_PING => expect_empty(payload).map(|_| ClientMessage::Ping),
+        TAG_HELLO => Ok(ClientMessage::Hello(from_json::<Hello>(payload)?)),
+        other => Err(FrameError::UnknownType(other)),
+    }
+}
+
+pub fn decode_server(tag: u8, payload: &[u8]) -> Result<ServerResponse, FrameError> {
+    match tag {
+        TAG_RESPONSE => match from_json(payload)? {
+            ServerResponse::Pong => Err(FrameError::Malformed("pong in a response frame".into())),
+            resp => Ok(resp),
+        },
+        TAG_PONG => expect_empty(payload).map(|_| ServerResponse::Pong),
+        other => Err(FrameError::UnknownType(other)),
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Vec<u8>), FrameError> {
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header).await?;
+    let tag = header[0];
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    if len > MAX_FRAME_LEN {
+        return Err(FrameError::Oversized { len });
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok((tag, payload))
+}
+
+fn encode(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(tag);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, FrameError> {
+    let json = serde_json::to_vec(value).map_err(|e| FrameError::Malformed(e.to_string()))?;
+    if json.len() > MAX_FRAME_LEN as usize {
+        return Err(FrameError::Oversized {
+            len: json.len() as u32,
+        });
+    }
+    Ok(json)
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(payload: &[u8]) -> Result<T, FrameError> {
+    serde_json::from_slice(payload).map_err(|e| FrameError::Malformed(e.to_string()))
+}
+
+fn expect_empty(payload: &[u8]) -> Result<(), FrameError> {
+    if payload.is_empty() {
+        Ok(())
+    } else {
+        Err(FrameError::Malformed("unexpected payload".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ConflictPolicy, ErrorCategory};
+
+    async fn client_round_trip(msg: &ClientMessage) -> ClientMessage {
+        let mut buf = Vec::new();
+        write_client(&mut buf, msg).await.unwrap();
+        read_client(&mut buf.as_slice()).await.unwrap()
+    }
+
+    async fn server_round_trip(resp: &ServerResponse) -> ServerResponse {
+        let mut buf = Vec::new();
+        write_server(&mut buf, resp).await.unwrap();
+        read_server(&mut buf.as_slice()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn client_messages_round_trip() {
+        let file = ClientMessage::File(FileMetadata {
+            relative_path: "dir/a.txt".into(),
+            size: 42,
+            is_dir: false,
+            mtime: Some(1_700_000_000),
+        });
+        match client_round_trip(&file).await {
+            ClientMessage::File(meta) => {
+                assert_eq!(meta.relative_path, "dir/a.txt");
+                assert_eq!(meta.size, 42);
+                assert!(!meta.is_dir);
+                assert_eq!(meta.mtime, Some(1_700_000_000));
+            }
+            other => panic!("got {:?}", other),
+        }
+
+        assert!(matches!(
+            client_round_trip(&ClientMessage::Ping).await,
+            ClientMessage::Ping
+        ));
+
+        let hello = ClientMessage::Hello(Hello {
+            conflict: Some(ConflictPolicy::KeepBoth),
+            session: Some("abc".into()),
+            total_bytes: Some(7),
+            acks: true,
+        });
+        match client_round_trip(&hello).await {
+            ClientMessage::Hello(hello) => {
+                assert_eq!(hello.conflict, Some(ConflictPolicy::KeepBoth));
+                assert_eq!(hello.session.as_deref(), Some("abc"));
+                assert_eq!(hello.total_bytes, Some(7));
+                assert!(hello.acks);
+            }
+            other => panic!("got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn server_responses_round_trip() {
+        assert!(matches!(
+            server_round_trip(&ServerResponse::Send).await,
+            ServerResponse::Send
+        ));
+        assert!(matches!(
+            server_round_trip(&ServerResponse::Pong).await,
+            ServerResponse::Pong
+        ));
+        assert!(matches!(
+            server_round_trip(&ServerResponse::Resume { offset: 9 }).await,
+            ServerResponse::Resume { offset: 9 }
+        ));
+        assert!(matches!(
+            server_round_trip(&ServerResponse::Stored { files: 3 }).await,
+            ServerResponse::Stored { files: 3 }
+        ));
+        let error = ServerResponse::Error {
+            message: "full".into(),
+            category: ErrorCategory::OutOfSpace,
+            relative_path: Some("a/b.bin".into()),
+        };
+        match server_round_trip(&error).await {
+            ServerResponse::Error {
+                message,
+                category,
+                relative_path,
+            } => {
+                assert_eq!(message, "full");
+                assert_eq!(category, ErrorCategory::OutOfSpace);
+                assert_eq!(relative_path.as_deref(), Some("a/b.bin"));
+            }
+            other => panic!("got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected_before_reading_it() {
+        // The header promises 4 GiB that never arrives; allocating or reading
+        // it would fail differently
+        let mut frame = vec![TAG_FILE];
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        match read_client(&mut frame.as_slice()).await {
+            Err(FrameError::Oversized { len }) => assert_eq!(len, u32::MAX),
+            other => panic!("got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        assert!(matches!(
+            decode_client(0x7f, b"{}"),
+            Err(FrameError::UnknownType(0x7f))
+        ));
+        assert!(matches!(
+            decode_server(TAG_FILE, b"{}"),
+            Err(FrameError::UnknownType(TAG_FILE))
+        ));
+    }
+
+    #[test]
+    fn malformed_json_is_rejected() {
+        assert!(matches!(
+            decode_client(TAG_FILE, b"{\"relative_path\":"),
+            Err(FrameError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_server(TAG_RESPONSE, b"not json"),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn ping_and_pong_carry_no_payload() {
+        assert!(matches!(
+            decode_client(TAG_PING, b"x"),
+            Err(FrameError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_server(TAG_PONG, b"x"),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn pong_in_a_response_frame_is_rejected() {
+        let json = serde_json::to_vec(&ServerResponse::Pong).unwrap();
+        assert!(matches!(
+            decode_server(TAG_RESPONSE, &json),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+}
@@ -1,4 +1,18 @@
-use rusqlite::{Connection, Result, params};
+use rusqlite::{Connection, OptionalExtension, Result, Transaction, params};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Status changes are written in one transaction per batch. A crash loses at
+/// most the batch being built, and those files are simply checked again.
+const BATCH_FILES: usize = 1000;
+const BATCH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Rows per transaction when adding scanned files; they are also written
+/// every `BATCH_INTERVAL` so a sender running alongside the scan sees them.
+const INSERT_BATCH: usize = 5000;
+/// Pending files fetched per query.
+const PENDING_PAGE: usize = 1000;
 
 #[derive(Debug)]
 pub struct Transfer {
@@ -10,6 +24,11 @@ pub struct Transfer {
     pub created_at: String,
     pub listing_complete: bool,
     pub exclude_patterns: Option<String>,
+    /// Patterns the last scan read from ignore files, as JSON
+    pub found_excludes: Option<String>,
+    pub rate_limit: u64,
+    pub schedule: Option<String>,
+    pub respect_gitignore: bool,
 }
 
 #[derive(Debug)]
@@ -39,7 +58,11 @@ impl Db {
                 status TEXT NOT NULL,
                 created_at TEXT DEFAULT CURRENT_TIMESTAMP,
                 listing_complete BOOLEAN DEFAULT 0,
-                exclude_patterns TEXT
+                exclude_patterns TEXT,
+                rate_limit INTEGER DEFAULT 0,
+                schedule TEXT,
+                respect_gitignore BOOLEAN DEFAULT 0,
+                found_excludes TEXT
             )",
             [],
         )?;
@@ -51,6 +74,16 @@ impl Db {
             [],
         );
         let _ = conn.execute("ALTER TABLE history ADD COLUMN exclude_patterns TEXT", []);
+        let _ = conn.execute(
+            "ALTER TABLE history ADD COLUMN rate_limit INTEGER DEFAULT 0",
+            [],
+        );
+        let _ = conn.execute("ALTER TABLE history ADD COLUMN schedule TEXT", []);
+        let _ = conn.execute("ALTER TABLE history ADD COLUMN found_excludes TEXT", []);
+        let _ = conn.execute(
+            "ALTER TABLE history ADD COLUMN respect_gitignore BOOLEAN DEFAULT 0",
+            [],
+        );
         // Optimize performance
         let _: String = conn.query_row("PRAGMA journal_mode=WAL;", [], |row| row.get(0))?;
         conn.execute("PRAGMA synchronous=NORMAL;", [])?;
@@ -64,10 +97,12 @@ impl Db {
         ip: &str,
         port: u16,
         exclude_patterns: Option<String>,
+        rate_limit: u64,
+        schedule: Option<String>,
     ) -> Result<i64> {
         self.conn.execute(
-            "INSERT INTO history (path, ip, port, status, listing_complete, exclude_patterns) VALUES (?1, ?2, ?3, ?4, 0, ?5)",
-            params![path, ip, port, "Pending", exclude_patterns],
+            "INSERT INTO history (path, ip, port, status, listing_complete, exclude_patterns, rate_limit, schedule) VALUES (?1, ?2, ?3, ?4, 0, ?5, ?6, ?7)",
+            params![path, ip, port, "Pending", exclude_patterns, rate_limit, schedule],
         )?;
         Ok(self.conn.last_insert_rowid())
     }
@@ -88,6 +123,14 @@ impl Db {
         Ok(())
     }
 
+    pub fn set_respect_gitignore(&self, id: i64, respect: bool) -> Result<()> {
+        self.conn.execute(
+            "UPDATE history SET respect_gitignore = ?2 WHERE id = ?1",
+            params![id, respect],
+        )?;
+        Ok(())
+    }
+
     pub fn update_excludes(&self, id: i64, patterns: String) -> Result<()> {
         self.conn.execute(
             "UPDATE history SET exclude_patterns = ?2 WHERE id = ?1",
@@ -96,6 +139,27 @@ impl Db {
         Ok(())
     }
 
+    pub fn update_found_excludes(&self, id: i64, patterns: String) -> Result<()> {
+        self.conn.execute(
+            "UPDATE history SET found_excludes = ?2 WHERE id = ?1",
+            params![id, patterns],
+        )?;
+        Ok(())
+    }
+
+    pub fn update_throttle(
+        &self,
+        id: i64,
+        rate_limit: u64,
+        schedule: Option<String>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE history SET rate_limit = ?2, schedule = ?3 WHERE id = ?1",
+            params![id, rate_limit, schedule],
+        )?;
+        Ok(())
+    }
+
     pub fn delete_transfer(&self, id: i64) -> Result<()> {
         self.conn
             .execute("DELETE FROM history WHERE id = ?1", params![id])?;
@@ -104,7 +168,7 @@ impl Db {
 
     pub fn list_transfers(&self) -> Result<Vec<Transfer>> {
         let mut stmt = self.conn.prepare(
-            "SELECT id, path, ip, port, status, created_at, listing_complete, exclude_patterns FROM history ORDER BY id DESC",
+            "SELECT id, path, ip, port, status, created_at, listing_complete, exclude_patterns, rate_limit, schedule, respect_gitignore, found_excludes FROM history ORDER BY id DESC",
         )?;
         let transfer_iter = stmt.query_map([], |row| {
             Ok(Transfer {
@@ -116,6 +180,10 @@ impl Db {
                 created_at: row.get(5)?,
                 listing_complete: row.get(6)?,
                 exclude_patterns: row.get(7).ok(),
+                rate_limit: row.get::<_, Option<u64>>(8)?.unwrap_or(0),
+                schedule: row.get(9)?,
+                respect_gitignore: row.get::<_, Option<bool>>(10)?.unwrap_or(false),
+                found_excludes: row.get(11)?,
             })
         })?;
 
@@ -128,7 +196,7 @@ impl Db {
 
     pub fn get_transfer(&self, id: i64) -> Result<Transfer> {
         self.conn.query_row(
-            "SELECT id, path, ip, port, status, created_at, listing_complete, exclude_patterns FROM history WHERE id = ?1",
+            "SELECT id, path, ip, port, status, created_at, listing_complete, exclude_patterns, rate_limit, schedule, respect_gitignore, found_excludes FROM history WHERE id = ?1",
             params![id],
             |row| {
                 Ok(Transfer {
@@ -140,6 +208,10 @@ impl Db {
                     created_at: row.get(5)?,
                     listing_complete: row.get(6)?,
                     exclude_patterns: row.get(7).ok(),
+                    rate_limit: row.get::<_, Option<u64>>(8)?.unwrap_or(0),
+                    schedule: row.get(9)?,
+                    respect_gitignore: row.get::<_, Option<bool>>(10)?.unwrap_or(false),
+                found_excludes: row.get(11)?,
                 })
             },
         )
@@ -148,64 +220,163 @@ impl Db {
 
 pub struct TransferLog {
     conn: Connection,
+    /// Status changes not written yet: (file id, status, error)
+    batch: RefCell<Vec<(i64, &'static str, Option<String>)>>,
+    last_flush: Cell<Instant>,
 }
 
 impl TransferLog {
     pub fn new(transfer_id: i64) -> Result<Self> {
         let db_path = format!("send_history_{}.db", transfer_id);
-        let conn = Connection::open(db_path)?;
+        Self::open(Connection::open(db_path)?)
+    }
+
+    fn open(conn: Connection) -> Result<Self> {
         conn.execute(
             "CREATE TABLE IF NOT EXISTS files (
                 id INTEGER PRIMARY KEY,
                 relative_path TEXT UNIQUE NOT NULL,
                 size INTEGER NOT NULL,
                 is_dir BOOLEAN NOT NULL,
-                status TEXT NOT NULL DEFAULT 'Pending'
+                status TEXT NOT NULL DEFAULT 'Pending',
+                error TEXT,
+                mtime INTEGER
             )",
             [],
         )?;
+        // Logs created before per-file errors (and mtimes) were tracked
+        let _ = conn.execute("ALTER TABLE files ADD COLUMN error TEXT", []);
+        let _ = conn.execute("ALTER TABLE files ADD COLUMN mtime INTEGER", []);
 
         // Optimize performance for this log DB too
         let _: String = conn.query_row("PRAGMA journal_mode=WAL;", [], |row| row.get(0))?;
         conn.execute("PRAGMA synchronous=NORMAL;", [])?;
 
-        Ok(TransferLog { conn })
+        Ok(TransferLog {
+            conn,
+            batch: RefCell::new(Vec::new()),
+            last_flush: Cell::new(Instant::now()),
+        })
     }
 
     pub fn reset(&self) -> Result<()> {
+        self.batch.borrow_mut().clear();
         self.conn.execute("DELETE FROM files", [])?;
         Ok(())
     }
 
-    pub fn add_file(&self, relative_path: &str, size: u64, is_dir: bool) -> Result<()> {
-        self.conn.execute(
-            "INSERT OR IGNORE INTO files (relative_path, size, is_dir, status) VALUES (?1, ?2, ?3, 'Pending')",
-            params![relative_path, size, is_dir],
+    /// Starts adding scanned files, committed `INSERT_BATCH` rows at a time.
+    pub fn bulk_insert(&self) -> BulkInsert<'_> {
+        BulkInsert {
+            conn: &self.conn,
+            rows: Vec::new(),
+            last_write: Instant::now(),
+            rescan: false,
+            changes: ScanChanges::default(),
+        }
+    }
+
+    /// Like `bulk_insert`, for walking the source again: new files are
+    /// added, sent or skipped files whose size or mtime changed go back to
+    /// `Pending`, and `finish` marks files that weren't seen as `Missing`.
+    pub fn rescan(&self) -> Result<BulkInsert<'_>> {
+        self.flush()?;
+        self.conn.execute_batch(
+            "CREATE TEMP TABLE IF NOT EXISTS seen (id INTEGER PRIMARY KEY);
+             DELETE FROM temp.seen;",
         )?;
+        let mut inserts = self.bulk_insert();
+        inserts.rescan = true;
+        Ok(inserts)
+    }
+
+    pub fn mark_sent(&self, id: i64) -> Result<()> {
+        self.queue(id, "Sent", None)
+    }
+
+    pub fn mark_skipped(&self, id: i64) -> Result<()> {
+        self.queue(id, "Skipped", None)
+    }
+
+    pub fn mark_failed(&self, id: i64, error: &str) -> Result<()> {
+        self.queue(id, "Failed", Some(error.to_string()))
+    }
+
+    fn queue(&self, id: i64, status: &'static str, error: Option<String>) -> Result<()> {
+        let len = {
+            let mut batch = self.batch.borrow_mut();
+            batch.push((id, status, error));
+            batch.len()
+        };
+        if len >= BATCH_FILES || self.last_flush.get().elapsed() >= BATCH_INTERVAL {
+            self.flush()?;
+        }
         Ok(())
     }
 
-    pub fn mark_sent(&self, relative_path: &str) -> Result<()> {
+    /// Writes queued status changes. Reads flush first, so they always see
+    /// them; call it directly before pausing or exiting.
+    pub fn flush(&self) -> Result<()> {
+        let batch = std::mem::take(&mut *self.batch.borrow_mut());
+        self.last_flush.set(Instant::now());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            let mut stmt =
+                tx.prepare_cached("UPDATE files SET status = ?2, error = ?3 WHERE id = ?1")?;
+            for (id, status, error) in &batch {
+                stmt.execute(params![id, status, error])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Records the size of a file that changed since it was scanned.
+    pub fn update_size(&self, id: i64, size: u64) -> Result<()> {
         self.conn.execute(
-            "UPDATE files SET status = 'Sent' WHERE relative_path = ?1",
-            params![relative_path],
+            "UPDATE files SET size = ?2 WHERE id = ?1",
+            params![id, size],
         )?;
         Ok(())
     }
 
-    pub fn mark_skipped(&self, relative_path: &str) -> Result<()> {
+    /// Puts files that failed in an earlier run back in the queue.
+    pub fn requeue_failed(&self) -> Result<usize> {
+        self.flush()?;
         self.conn.execute(
-            "UPDATE files SET status = 'Skipped' WHERE relative_path = ?1",
-            params![relative_path],
-        )?;
-        Ok(())
+            "UPDATE files SET status = 'Pending', error = NULL WHERE status = 'Failed'",
+            [],
+        )
     }
 
-    pub fn get_pending_files(&self) -> Result<Vec<FileRecord>> {
+    pub fn get_failed_files(&self) -> Result<Vec<(String, String)>> {
+        self.flush()?;
         let mut stmt = self.conn.prepare(
-            "SELECT id, relative_path, size, is_dir, status FROM files WHERE status = 'Pending'",
+            "SELECT relative_path, COALESCE(error, '') FROM files WHERE status = 'Failed' ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    /// Pending files in id order, read a page at a time so a huge log never
+    /// has to fit in memory.
+    pub fn pending_files(&self) -> PendingFiles<'_> {
+        PendingFiles {
+            log: self,
+            page: VecDeque::new(),
+            last_id: 0,
+        }
+    }
+
+    fn pending_page(&self, after_id: i64) -> Result<VecDeque<FileRecord>> {
+        self.flush()?;
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, relative_path, size, is_dir, status FROM files
+             WHERE status = 'Pending' AND id > ?1 ORDER BY id LIMIT ?2",
         )?;
-        let rows = stmt.query_map([], |row| {
+        let rows = stmt.query_map(params![after_id, PENDING_PAGE], |row| {
             Ok(FileRecord {
                 id: row.get(0)?,
                 relative_path: row.get(1)?,
@@ -214,24 +385,20 @@ impl TransferLog {
                 status: row.get(4)?,
             })
         })?;
+        rows.collect()
+    }
 
-        // Wait, original FileRecord struct:
-        // pub struct FileRecord {
-        //     pub id: i64,
-        //     pub relative_path: String,
-        //     pub size: u64,
-        //     pub is_dir: bool,
-        //     pub status: String,
-        // }
-
-        let mut files = Vec::new();
-        for r in rows {
-            files.push(r?);
-        }
-        Ok(files)
+    pub fn pending_bytes(&self) -> Result<u64> {
+        self.flush()?;
+        self.conn.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM files WHERE status = 'Pending'",
+            [],
+            |row| row.get(0),
+        )
     }
 
     pub fn count_pending(&self) -> Result<u64> {
+        self.flush()?;
         self.conn.query_row(
             "SELECT COUNT(*) FROM files WHERE status = 'Pending'",
             [],
@@ -240,11 +407,13 @@ impl TransferLog {
     }
 
     pub fn count_total(&self) -> Result<u64> {
+        self.flush()?;
         self.conn
             .query_row("SELECT COUNT(*) FROM files", [], |row| row.get(0))
     }
 
     pub fn count_skipped(&self) -> Result<u64> {
+        self.flush()?;
         self.conn.query_row(
             "SELECT COUNT(*) FROM files WHERE status = 'Skipped'",
             [],
@@ -253,6 +422,7 @@ impl TransferLog {
     }
 
     pub fn get_total_sent_bytes(&self) -> Result<u64> {
+        self.flush()?;
         // Sum size of all files with status = 'Sent'
         self.conn.query_row(
             "SELECT COALESCE(SUM(size), 0) FROM files WHERE status = 'Sent'",
@@ -261,3 +431,330 @@ impl TransferLog {
         )
     }
 }
+
+/// Cursor from `TransferLog::pending_files`. Files added after it started
+/// are picked up as long as their ids are higher.
+pub struct PendingFiles<'a> {
+    log: &'a TransferLog,
+    page: VecDeque<FileRecord>,
+    last_id: i64,
+}
+
+impl Iterator for PendingFiles<'_> {
+    type Item = Result<FileRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() {
+            match self.log.pending_page(self.last_id) {
+                Ok(page) => self.page = page,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let record = self.page.pop_front()?;
+        self.last_id = record.id;
+        Some(Ok(record))
+    }
+}
+
+/// What a scan changed in the log.
+#[derive(Default)]
+pub struct ScanChanges {
+    /// Files that weren't logged (or had gone missing) before
+    pub new: u64,
+    /// Sent or skipped files that changed since, queued to go again
+    pub changed: u64,
+    /// Logged files a rescan didn't find
+    pub missing: u64,
+}
+
+/// Writer from `TransferLog::bulk_insert`. Rows are held here and written
+/// in one transaction, never left open across an await, so other users of
+/// the log can write in between. Whatever was added is kept even if it is
+/// dropped early (an interrupted scan); `finish` reports errors.
+pub struct BulkInsert<'a> {
+    conn: &'a Connection,
+    rows: Vec<(String, u64, bool, Option<i64>)>,
+    last_write: Instant,
+    /// Compare with the logged files rather than only adding new ones
+    rescan: bool,
+    changes: ScanChanges,
+}
+
+impl BulkInsert<'_> {
+    pub fn add(
+        &mut self,
+        relative_path: &str,
+        size: u64,
+        is_dir: bool,
+        mtime: Option<i64>,
+    ) -> Result<()> {
+        self.rows
+            .push((relative_path.to_string(), size, is_dir, mtime));
+        if self.rows.len() >= INSERT_BATCH || self.last_write.elapsed() >= BATCH_INTERVAL {
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self) -> Result<()> {
+        self.last_write = Instant::now();
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        for row in self.rows.drain(..) {
+            match self.rescan {
+                true => rescan_row(&tx, &mut self.changes, row)?,
+                false => self.changes.new += insert_row(&tx, &row)? as u64,
+            }
+        }
+        tx.commit()
+    }
+
+    /// Writes what is left. After a rescan, logged files it didn't see are
+    /// marked `Missing`, unless `excluded(path, is_dir)` says the walk left
+    /// them out on purpose.
+    pub fn finish(mut self, excluded: impl Fn(&str, bool) -> bool) -> Result<ScanChanges> {
+        self.write()?;
+        if self.rescan {
+            let unseen = self
+                .conn
+                .prepare(
+                    "SELECT id, relative_path, is_dir FROM files
+                     WHERE status != 'Missing' AND id NOT IN (SELECT id FROM temp.seen)",
+                )?
+                .query_map([], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, bool>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>>>()?;
+            let tx = self.conn.unchecked_transaction()?;
+            for (id, relative_path, is_dir) in unseen {
+                if !excluded(&relative_path, is_dir) {
+                    tx.prepare_cached("UPDATE files SET status = 'Missing' WHERE id = ?1")?
+                        .execute(params![id])?;
+                    self.changes.missing += 1;
+                }
+            }
+            tx.commit()?;
+            self.conn.execute("DROP TABLE temp.seen", [])?;
+        }
+        Ok(std::mem::take(&mut self.changes))
+    }
+}
+
+fn insert_row(tx: &Transaction, row: &(String, u64, bool, Option<i64>)) -> Result<usize> {
+    let (relative_path, size, is_dir, mtime) = row;
+    tx.prepare_cached(
+        "INSERT OR IGNORE INTO files (relative_path, size, is_dir, mtime, status) VALUES (?1, ?2, ?3, ?4, 'Pending')",
+    )?
+    .execute(params![relative_path, size, is_dir, mtime])
+}
+
+fn rescan_row(
+    tx: &Transaction,
+    changes: &mut ScanChanges,
+    row: (String, u64, bool, Option<i64>),
+) -> Result<()> {
+    let (relative_path, size, is_dir, mtime) = &row;
+    let logged = tx
+        .prepare_cached("SELECT id, status, size, mtime FROM files WHERE relative_path = ?1")?
+        .query_row(params![relative_path], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+            ))
+        })
+        .optional()?;
+    let id = match logged {
+        None => {
+            insert_row(tx, &row)?;
+            changes.new += 1;
+            tx.last_insert_rowid()
+        }
+        Some((id, status, logged_size, logged_mtime)) => {
+            // Rows from before mtimes were kept only have the size to go on.
+            // A directory's mtime moves whenever its entries do; ignore it
+            let modified = !*is_dir
+                && (logged_size != *size || logged_mtime.is_some_and(|m| Some(m) != *mtime));
+            let requeue = match status.as_str() {
+                "Missing" => {
+                    changes.new += 1;
+                    true
+                }
+                "Sent" | "Skipped" if modified => {
+                    changes.changed += 1;
+                    true
+                }
+                _ => false,
+            };
+            if requeue || logged_size != *size || logged_mtime != *mtime {
+                tx.prepare_cached(
+                    "UPDATE files SET size = ?2, is_dir = ?3, mtime = ?4,
+                     status = CASE WHEN ?5 THEN 'Pending' ELSE status END,
+                     error = CASE WHEN ?5 THEN NULL ELSE error END
+                     WHERE id = ?1",
+                )?
+                .execute(params![id, size, is_dir, mtime, requeue])?;
+            }
+            id
+        }
+    };
+    tx.prepare_cached("INSERT OR IGNORE INTO temp.seen (id) VALUES (?1)")?
+        .execute(params![id])?;
+    Ok(())
+}
+
+impl Drop for BulkInsert<'_> {
+    fn drop(&mut self) {
+        let _ = self.write();
+    }
+}
+
+impl Drop for TransferLog {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_with(files: &[(&str, u64, bool, Option<i64>)]) -> TransferLog {
+        let log = TransferLog::open(Connection::open_in_memory().unwrap()).unwrap();
+        let mut inserts = log.bulk_insert();
+        for (path, size, is_dir, mtime) in files {
+            inserts.add(path, *size, *is_dir, *mtime).unwrap();
+        }
+        inserts.finish(|_, _| false).unwrap();
+        log
+    }
+
+    fn id(log: &TransferLog, path: &str) -> i64 {
+        log.conn
+            .query_row(
+                "SELECT id FROM files WHERE relative_path = ?1",
+                params![path],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn status(log: &TransferLog, path: &str) -> String {
+        log.flush().unwrap();
+        log.conn
+            .query_row(
+                "SELECT status FROM files WHERE relative_path = ?1",
+                params![path],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    fn rescan(
+        log: &TransferLog,
+        files: &[(&str, u64, bool, Option<i64>)],
+        excluded: impl Fn(&str, bool) -> bool,
+    ) -> ScanChanges {
+        let mut inserts = log.rescan().unwrap();
+        for (path, size, is_dir, mtime) in files {
+            inserts.add(path, *size, *is_dir, *mtime).unwrap();
+        }
+        inserts.finish(excluded).unwrap()
+    }
+
+    #[test]
+    fn new_files_are_added_as_pending() {
+        let log = log_with(&[("s/a", 1, false, Some(10))]);
+        log.mark_sent(id(&log, "s/a")).unwrap();
+        let changes = rescan(
+            &log,
+            &[("s/a", 1, false, Some(10)), ("s/b", 2, false, Some(10))],
+            |_, _| false,
+        );
+        assert_eq!((changes.new, changes.changed, changes.missing), (1, 0, 0));
+        assert_eq!(status(&log, "s/a"), "Sent");
+        assert_eq!(status(&log, "s/b"), "Pending");
+    }
+
+    #[test]
+    fn sent_and_skipped_files_that_changed_are_queued_again() {
+        let files = [
+            ("s/size", 1, false, Some(10)),
+            ("s/mtime", 1, false, Some(10)),
+            ("s/skipped", 1, false, Some(10)),
+            ("s/same", 1, false, Some(10)),
+            ("s/pending", 1, false, Some(10)),
+        ];
+        let log = log_with(&files);
+        for path in ["s/size", "s/mtime", "s/same"] {
+            log.mark_sent(id(&log, path)).unwrap();
+        }
+        log.mark_skipped(id(&log, "s/skipped")).unwrap();
+        let changes = rescan(
+            &log,
+            &[
+                ("s/size", 2, false, Some(10)),
+                ("s/mtime", 1, false, Some(11)),
+                ("s/skipped", 1, false, Some(11)),
+                ("s/same", 1, false, Some(10)),
+                ("s/pending", 5, false, Some(11)),
+            ],
+            |_, _| false,
+        );
+        assert_eq!((changes.new, changes.changed, changes.missing), (0, 3, 0));
+        for path in ["s/size", "s/mtime", "s/skipped", "s/pending"] {
+            assert_eq!(status(&log, path), "Pending", "{}", path);
+        }
+        assert_eq!(status(&log, "s/same"), "Sent");
+        // The new size is what gets sent
+        assert_eq!(log.pending_bytes().unwrap(), 2 + 1 + 1 + 5);
+    }
+
+    #[test]
+    fn missing_files_that_come_back_are_new_again() {
+        let log = log_with(&[("s/a", 1, false, Some(10))]);
+        log.mark_sent(id(&log, "s/a")).unwrap();
+        let changes = rescan(&log, &[], |_, _| false);
+        assert_eq!(changes.missing, 1);
+        assert_eq!(status(&log, "s/a"), "Missing");
+
+        let changes = rescan(&log, &[("s/a", 1, false, Some(10))], |_, _| false);
+        assert_eq!((changes.new, changes.changed, changes.missing), (1, 0, 0));
+        assert_eq!(status(&log, "s/a"), "Pending");
+    }
+
+    #[test]
+    fn unseen_files_are_missing_unless_excluded() {
+        let log = log_with(&[
+            ("s/gone", 1, false, None),
+            ("s/logs", 0, true, None),
+            ("s/logs/x.log", 1, false, None),
+        ]);
+        for path in ["s/gone", "s/logs", "s/logs/x.log"] {
+            log.mark_sent(id(&log, path)).unwrap();
+        }
+        let changes = rescan(&log, &[], |path, _| path.starts_with("s/logs"));
+        assert_eq!(changes.missing, 1);
+        assert_eq!(status(&log, "s/gone"), "Missing");
+        assert_eq!(status(&log, "s/logs"), "Sent");
+        assert_eq!(status(&log, "s/logs/x.log"), "Sent");
+        // Already missing isn't counted twice
+        assert_eq!(rescan(&log, &[], |_, _| false).missing, 2);
+    }
+
+    #[test]
+    fn directory_mtimes_are_not_changes() {
+        let log = log_with(&[("s", 4096, true, Some(10))]);
+        log.mark_sent(id(&log, "s")).unwrap();
+        let changes = rescan(&log, &[("s", 4096, true, Some(20))], |_, _| false);
+        assert_eq!(changes.changed, 0);
+        assert_eq!(status(&log, "s"), "Sent");
+    }
+}
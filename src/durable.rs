@@ -0,0 +1,39 @@
+//! Getting received files onto stable storage before they take their final
+//! name, so a power cut can't leave a truncated file that looks complete.
+
+use crate::paths::{Access, Dir, Entry};
+use anyhow::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Durability {
+    /// Leave write-back to the OS (fastest)
+    Off,
+    /// fsync each file before the rename and its directory after
+    Full,
+    /// Sync finished files in groups, then rename them together
+    Batch,
+}
+
+/// Flushes a directory entry change (a rename) to disk.
+pub async fn sync_dir(dir: &Dir) -> Result<()> {
+    // Windows can't open directories as files; NTFS journals renames anyway
+    #[cfg(unix)]
+    dir.sync().await?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Flushes the data of every file in `files`. The syncs run side by side on
+/// the blocking pool, so the disk can take them in whatever order suits it,
+/// and only these files are waited for, not everything else on the
+/// filesystem.
+pub async fn sync_files(files: Vec<Entry>) -> Result<()> {
+    let syncs = files
+        .into_iter()
+        .map(|file| async move { file.open(Access::Read).await?.sync_data().await });
+    for result in futures::future::join_all(syncs).await {
+        result?;
+    }
+    Ok(())
+}
@@ -0,0 +1,324 @@
+//! Exclude patterns with `.gitignore` semantics. Paths are `/`-separated and
+//! relative to the root the patterns were written for:
+//!
+//! * a pattern without a `/` (`node_modules`, `*.log`) matches a name at any
+//!   depth; one with a `/` in it (`build/out`, `/target`) is anchored to the
+//!   root, and `**` spans any number of directories
+//! * a trailing `/` (`cache/`) only matches directories
+//! * a leading `!` brings back something an earlier pattern excluded
+//!
+//! The last pattern that matches wins. Excluding a directory excludes
+//! everything in it, and nothing inside can be brought back.
+//!
+//! Patterns can also come from ignore files met during the walk
+//! (`.gitignore`, `.sendignore`). Those are rewritten relative to the root,
+//! so they can be saved and given back with `add_found`, and rank below the
+//! patterns the user gave.
+
+use glob::{MatchOptions, Pattern};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+const MATCH: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+struct Rule {
+    glob: Pattern,
+    negate: bool,
+    dir_only: bool,
+    /// Matched against the whole path rather than just the name
+    anchored: bool,
+}
+
+#[derive(Default)]
+pub struct IgnoreRules {
+    /// From ignore files, in the order they were read
+    found: Vec<Rule>,
+    /// From the user; these win over `found`
+    given: Vec<Rule>,
+    /// The patterns behind `found`
+    found_patterns: Vec<String>,
+    known: HashSet<String>,
+}
+
+impl IgnoreRules {
+    /// Compiles `patterns`, leaving out blank lines, `#` comments and
+    /// anything that isn't a valid glob.
+    pub fn new(patterns: &[String]) -> Self {
+        IgnoreRules {
+            found: Vec::new(),
+            given: patterns.iter().filter_map(|p| Rule::parse(p)).collect(),
+            found_patterns: Vec::new(),
+            known: patterns.iter().cloned().collect(),
+        }
+    }
+
+    /// Adds the patterns in the ignore file `file`, which lives in `dir`
+    /// (relative to the root, empty for the root itself). A missing or
+    /// unreadable file adds nothing.
+    pub async fn load(&mut self, dir: &str, file: &Path) {
+        let Ok(text) = tokio::fs::read_to_string(file).await else {
+            return;
+        };
+        for line in text.lines() {
+            let Some(pattern) = scoped(dir, line) else {
+                continue;
+            };
+            // A rescan meets the same files again
+            if !self.known.insert(pattern.clone()) {
+                continue;
+            }
+            self.add(pattern);
+        }
+    }
+
+    /// Adds patterns an earlier walk found in ignore files (`found`).
+    pub fn add_found(&mut self, patterns: &[String]) {
+        for pattern in patterns {
+            if self.known.insert(pattern.clone()) {
+                self.add(pattern.clone());
+            }
+        }
+    }
+
+    fn add(&mut self, pattern: String) {
+        if let Some(rule) = Rule::parse(&pattern) {
+            self.found.push(rule);
+            self.found_patterns.push(pattern);
+        }
+    }
+
+    /// The patterns read from ignore files so far, already rewritten
+    /// relative to the root, lowest priority first.
+    pub fn found(&self) -> &[String] {
+        &self.found_patterns
+    }
+
+    /// Whether `path` itself is excluded, assuming its parent directories
+    /// were already checked (as a walk that skips excluded ones does).
+    pub fn excludes(&self, path: &str, is_dir: bool) -> bool {
+        if path.is_empty() {
+            return false;
+        }
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.given
+            .iter()
+            .rev()
+            .chain(self.found.iter().rev())
+            .find(|rule| rule.matches(path, name, is_dir))
+            .is_some_and(|rule| !rule.negate)
+    }
+
+    /// Whether `path` or any directory above it is excluded.
+    pub fn excludes_with_parents(&self, path: &str, is_dir: bool) -> bool {
+        let parents = path
+            .match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .any(|dir| self.excludes(dir, true));
+        parents || self.excludes(path, is_dir)
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            // `\!` and `\#` start a pattern with a literal `!` or `#`
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Rule {
+            glob: Pattern::new(line).ok()?,
+            negate,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &str, name: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        match self.anchored {
+            true => self.glob.matches_with(path, MATCH),
+            false => self.glob.matches_with(name, MATCH),
+        }
+    }
+}
+
+/// Rewrites a line from an ignore file in `dir` to mean the same thing when
+/// matched from the root.
+fn scoped(dir: &str, line: &str) -> Option<String> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if dir.is_empty() {
+        return Some(line.to_string());
+    }
+    let (bang, pattern) = match line.strip_prefix('!') {
+        Some(rest) => ("!", rest),
+        None => ("", line.strip_prefix('\\').unwrap_or(line)),
+    };
+    let dir = Pattern::escape(dir);
+    let anchored = pattern.trim_end_matches('/').contains('/');
+    Some(match anchored {
+        true => format!("{}{}/{}", bang, dir, pattern.trim_start_matches('/')),
+        false => format!("{}{}/**/{}", bang, dir, pattern),
+    })
+}
+
+/// Git's global excludes file: `core.excludesFile` as git sees it from
+/// `repo`, or its default place under the user's config directory.
+pub async fn global_excludes(repo: &Path) -> Option<PathBuf> {
+    let configured = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(["config", "--path", "--get", "core.excludesFile"])
+        .output()
+        .await
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .filter(|path| !path.is_empty());
+    if let Some(path) = configured {
+        return Some(PathBuf::from(path));
+    }
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .or_else(|| std::env::var_os("USERPROFILE"))
+                .map(|home| PathBuf::from(home).join(".config"))
+        })?;
+    Some(config.join("git").join("ignore"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(patterns: &[&str]) -> IgnoreRules {
+        IgnoreRules::new(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn bare_names_match_at_any_depth() {
+        let rules = compile(&["node_modules", "*.tmp"]);
+        assert!(rules.excludes("node_modules", true));
+        assert!(rules.excludes("a/b/node_modules", true));
+        assert!(rules.excludes("x.tmp", false));
+        assert!(rules.excludes("a/x.tmp", false));
+        assert!(!rules.excludes("a/x.txt", false));
+    }
+
+    #[test]
+    fn a_slash_anchors_to_the_root() {
+        let rules = compile(&["/build", "docs/out"]);
+        assert!(rules.excludes("build", true));
+        assert!(!rules.excludes("src/build", true));
+        assert!(rules.excludes("docs/out", true));
+        assert!(!rules.excludes("a/docs/out", true));
+        assert!(!rules.excludes("out", true));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_dirs() {
+        let rules = compile(&["cache/"]);
+        assert!(rules.excludes("cache", true));
+        assert!(rules.excludes("a/cache", true));
+        assert!(!rules.excludes("cache", false));
+    }
+
+    #[test]
+    fn last_match_wins_and_negation_brings_back() {
+        let rules = compile(&["*.log", "!keep.log"]);
+        assert!(rules.excludes("a.log", false));
+        assert!(!rules.excludes("keep.log", false));
+        assert!(!rules.excludes("a/keep.log", false));
+
+        let rules = compile(&["!keep.log", "*.log"]);
+        assert!(rules.excludes("keep.log", false));
+    }
+
+    #[test]
+    fn nothing_comes_back_from_an_excluded_dir() {
+        let rules = compile(&["logs/", "!logs/keep.log"]);
+        assert!(rules.excludes_with_parents("logs/keep.log", false));
+        assert!(rules.excludes_with_parents("logs/a/b.log", false));
+        assert!(!rules.excludes_with_parents("src/keep.log", false));
+    }
+
+    #[test]
+    fn double_star_patterns_keep_working() {
+        let rules = compile(&["**/node_modules/**"]);
+        assert!(rules.excludes_with_parents("a/node_modules/x.js", false));
+        assert!(rules.excludes_with_parents("node_modules/x.js", false));
+        assert!(!rules.excludes_with_parents("a/src/x.js", false));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_ignored() {
+        let rules = compile(&["# *.txt", "", "   "]);
+        assert!(!rules.excludes("a.txt", false));
+        assert!(rules.found().is_empty());
+    }
+
+    #[test]
+    fn scoped_rewrites_relative_to_the_root() {
+        assert_eq!(scoped("", "*.o").as_deref(), Some("*.o"));
+        assert_eq!(scoped("sub", "*.o").as_deref(), Some("sub/**/*.o"));
+        assert_eq!(scoped("sub", "build/").as_deref(), Some("sub/**/build/"));
+        assert_eq!(scoped("sub", "/out").as_deref(), Some("sub/out"));
+        assert_eq!(scoped("sub", "a/b").as_deref(), Some("sub/a/b"));
+        assert_eq!(scoped("sub", "!keep.o").as_deref(), Some("!sub/**/keep.o"));
+        assert_eq!(scoped("a[1]", "x").as_deref(), Some("a[[]1[]]/**/x"));
+        assert_eq!(scoped("sub", "# note"), None);
+        assert_eq!(scoped("sub", ""), None);
+    }
+
+    #[test]
+    fn scoped_patterns_only_apply_below_their_dir() {
+        let mut rules = compile(&[]);
+        let found: Vec<String> = ["*.o", "/out", "!keep.o"]
+            .iter()
+            .filter_map(|line| scoped("sub", line))
+            .collect();
+        rules.add_found(&found);
+        assert!(rules.excludes("sub/x.o", false));
+        assert!(rules.excludes("sub/d/y.o", false));
+        assert!(!rules.excludes("x.o", false));
+        assert!(!rules.excludes("sub/keep.o", false));
+        assert!(rules.excludes("sub/out", true));
+        assert!(!rules.excludes("sub/d/out", true));
+        assert_eq!(rules.found(), found.as_slice());
+    }
+
+    #[test]
+    fn given_patterns_win_over_found_ones() {
+        let mut rules = compile(&["*.log"]);
+        rules.add_found(&["!a.log".to_string()]);
+        assert!(rules.excludes("a.log", false));
+
+        let mut rules = compile(&["!a.log"]);
+        rules.add_found(&["*.log".to_string()]);
+        assert!(!rules.excludes("a.log", false));
+        assert!(rules.excludes("b.log", false));
+    }
+}
@@ -1,13 +1,29 @@
+mod access;
 mod cli;
 mod client;
+mod codec;
 mod db;
+mod durable;
+mod ignore;
+mod net;
+mod paths;
 mod protocol;
 mod server;
+mod shutdown;
+mod staging;
+mod throttle;
+mod uring;
+mod versions;
+mod walk;
+mod zerocopy;
 
 use anyhow::Result;
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, StagingAction, TransferArgs, VersionsAction};
 use db::Db;
+use shutdown::{Interrupted, Shutdown};
+use std::time::Duration;
+use throttle::Schedule;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -16,14 +32,66 @@ async fn main() -> Result<()> {
     let db = Db::init()?;
 
     match cli.command {
-        Commands::Serve { path, port } => {
-            server::run_server(path, port).await?;
+        Commands::Serve {
+            path,
+            port,
+            timeout,
+            idle_timeout,
+            keepalive,
+            bind,
+            interface,
+            max_sessions,
+            allow,
+            deny,
+            client_rate,
+            client_max_files,
+            conflict,
+            keep_versions,
+            keep_versions_days,
+            stale_partials,
+            durable,
+            space_check,
+            no_preallocate,
+            no_zero_copy,
+            buffers,
+            io_backend,
+        } => {
+            let options = server::ServerOptions {
+                timeouts: net::Timeouts {
+                    io: Duration::from_secs(timeout),
+                    keepalive: Duration::from_secs(keepalive),
+                },
+                idle_timeout: Duration::from_secs(idle_timeout),
+                bind,
+                interface,
+                max_sessions,
+                access: access::AccessList { allow, deny },
+                client_rate,
+                client_max_files,
+                conflict,
+                versions: (keep_versions.is_some() || keep_versions_days.is_some()).then_some(
+                    versions::Retention {
+                        keep: keep_versions,
+                        days: keep_versions_days,
+                    },
+                ),
+                stale_partials: Duration::from_secs(stale_partials * 3600),
+                durability: durable,
+                space_check,
+                preallocate: !no_preallocate,
+                zero_copy: !no_zero_copy,
+                buffers: buffers as usize,
+                io_backend,
+            };
+            server::run_server(path, port, options, Shutdown::listen()).await?;
         }
         Commands::Push {
             path,
             ip,
             port,
             exclude,
+            respect_gitignore,
+            transfer,
         } => {
             let abs_path = std::fs::canonicalize(&path).unwrap_or(path.clone());
 
@@ -33,18 +101,72 @@ async fn main() -> Result<()> {
                 None
             };
 
-            let id = db.add_transfer(&abs_path.to_string_lossy(), &ip, port, exclude_json)?;
+            let rate_limit = transfer.limit.unwrap_or(0);
+            let schedule = transfer.schedule.clone().filter(|s| !s.is_empty());
+            let id = db.add_transfer(
+                &abs_path.to_string_lossy(),
+                &ip,
+                port,
+                exclude_json,
+                rate_limit,
+                schedule.as_ref().map(|s| s.to_string()),
+            )?;
             println!("Transfer started with ID: {}", id);
+            if respect_gitignore {
+                db.set_respect_gitignore(id, true)?;
+            }
+            let session = session_key(&db.get_transfer(id)?);
+            let shutdown = Shutdown::listen();
 
             let log = db::TransferLog::new(id)?;
-            client::scan_files(abs_path.clone(), &log, &exclude).await?;
-            db.set_listing_complete(id, true)?;
-
-            match client::send_pending_files(abs_path, ip, port, &log, &exclude).await {
+            let scan_path = abs_path.clone();
+            let scan = async {
+                let found = client::scan_files(
+                    scan_path,
+                    &log,
+                    &exclude,
+                    transfer.scan_threads as usize,
+                    false,
+                    respect_gitignore,
+                    &shutdown,
+                )
+                .await?;
+                db.update_found_excludes(id, serde_json::to_string(&found)?)?;
+                db.set_listing_complete(id, true)?;
+                anyhow::Ok(())
+            };
+            let listing_complete = || Ok(db.get_transfer(id)?.listing_complete);
+            let options = send_options(&transfer, session, rate_limit, schedule);
+            let send = client::send_pending_files(
+                abs_path,
+                ip,
+                port,
+                &log,
+                &exclude,
+                &[],
+                &options,
+                &listing_complete,
+                &shutdown,
+            );
+            let result = match scan_then_send(transfer.overlap, scan, send).await {
+                Ok(result) => result,
+                Err(e) if e.is::<Interrupted>() => return pause(&db, id),
+                Err(e) => return Err(e),
+            };
+            // Statuses are written in batches; store the last one before
+            // the transfer is marked paused, failed or done
+            log.flush()?;
+            match result {
                 Ok(_) => {
                     db.update_status(id, "Completed")?;
                     println!("Transfer completed successfully.");
                 }
+                Err(e) if e.is::<Interrupted>() => pause(&db, id)?,
+                Err(e) if e.is::<client::FilesFailed>() => {
+                    // Exit non-zero so scripts notice; `resume` retries them
+                    db.update_status(id, "Failed")?;
+                    return Err(e);
+                }
                 Err(e) => {
                     db.update_status(id, "Failed")?;
                     eprintln!("\nTransfer failed: {}", e);
@@ -64,8 +186,17 @@ async fn main() -> Result<()> {
                 );
             }
         }
-        Commands::Resume { id, exclude } => {
+        Commands::Resume {
+            id,
+            exclude,
+            rescan,
+            transfer: transfer_args,
+        } => {
             let transfer = db.get_transfer(id)?;
+            let respect_gitignore = transfer.respect_gitignore;
+            let shutdown = Shutdown::listen();
+            let (rate_limit, schedule) = throttle_settings(&db, &transfer, &transfer_args)?;
+            let session = session_key(&transfer);
 
             // Determine exclude patterns
             // If provided in CLI -> use them and update DB
@@ -84,36 +215,97 @@ async fn main() -> Result<()> {
             let log = db::TransferLog::new(id)?;
             let path = std::path::PathBuf::from(transfer.path);
 
-            if !transfer.listing_complete {
+            let requeued = log.requeue_failed()?;
+            if requeued > 0 {
+                println!("Retrying {} file(s) that failed previously.", requeued);
+            }
+
+            let listed = transfer.listing_complete && !rescan;
+            // What the last scan read from ignore files. A scan run now reads
+            // them again, and only logs what they let through
+            let found_excludes: Vec<String> = match listed {
+                true => transfer
+                    .found_excludes
+                    .as_deref()
+                    .and_then(|json| serde_json::from_str(json).ok())
+                    .unwrap_or_default(),
+                false => Vec::new(),
+            };
+            if rescan {
+                println!("Rescanning for new, changed and missing files...");
+                // Keeps the sender waiting under --overlap, and a rescan cut
+                // short is picked up by the next resume
+                db.set_listing_complete(id, false)?;
+            } else if !listed {
                 println!("Listing was incomplete. Resuming scan...");
-                client::scan_files(path.clone(), &log, &final_excludes).await?;
-                db.set_listing_complete(id, true)?;
             } else {
                 println!("Listing complete. Checking pending files...");
             }
-
-            match client::send_pending_files(
+            let scan_path = path.clone();
+            let scan = async {
+                if !listed {
+                    let found = client::scan_files(
+                        scan_path,
+                        &log,
+                        &final_excludes,
+                        transfer_args.scan_threads as usize,
+                        rescan,
+                        respect_gitignore,
+                        &shutdown,
+                    )
+                    .await?;
+                    db.update_found_excludes(id, serde_json::to_string(&found)?)?;
+                    db.set_listing_complete(id, true)?;
+                }
+                anyhow::Ok(())
+            };
+            let listing_complete = || Ok(db.get_transfer(id)?.listing_complete);
+            let options = send_options(&transfer_args, session, rate_limit, schedule);
+            let send = client::send_pending_files(
                 path,
                 transfer.ip,
                 transfer.port,
                 &log,
                 &final_excludes,
-            )
-            .await
-            {
+                &found_excludes,
+                &options,
+                &listing_complete,
+                &shutdown,
+            );
+            let result = match scan_then_send(transfer_args.overlap, scan, send).await {
+                Ok(result) => result,
+                Err(e) if e.is::<Interrupted>() => return pause(&db, id),
+                Err(e) => return Err(e),
+            };
+            log.flush()?;
+            match result {
                 Ok(_) => {
                     db.update_status(id, "Completed")?;
                     println!("Transfer resumed and completed.");
                 }
+                Err(e) if e.is::<Interrupted>() => pause(&db, id)?,
+                Err(e) if e.is::<client::FilesFailed>() => {
+                    // Exit non-zero so scripts notice; `resume` retries them
+                    db.update_status(id, "Failed")?;
+                    return Err(e);
+                }
                 Err(e) => {
                     // Keep status properly? status is just string.
                     eprintln!("\nTransfer interrupted/failed: {}", e);
                 }
             }
         }
-        Commands::Restart { id, exclude } => {
+        Commands::Restart {
+            id,
+            exclude,
+            transfer: transfer_args,
+        } => {
             let transfer = db.get_transfer(id)?;
+            let respect_gitignore = transfer.respect_gitignore;
+            let shutdown = Shutdown::listen();
             println!("Restarting transfer ID: {}", id);
+            let (rate_limit, schedule) = throttle_settings(&db, &transfer, &transfer_args)?;
+            let session = session_key(&transfer);
 
             // Same logic as Resume
             let mut final_excludes = exclude;
@@ -134,28 +326,110 @@ async fn main() -> Result<()> {
 
             let path = std::path::PathBuf::from(transfer.path);
 
-            client::scan_files(path.clone(), &log, &final_excludes).await?;
-            db.set_listing_complete(id, true)?;
-
-            match client::send_pending_files(
+            let scan_path = path.clone();
+            let scan = async {
+                let found = client::scan_files(
+                    scan_path,
+                    &log,
+                    &final_excludes,
+                    transfer_args.scan_threads as usize,
+                    false,
+                    respect_gitignore,
+                    &shutdown,
+                )
+                .await?;
+                db.update_found_excludes(id, serde_json::to_string(&found)?)?;
+                db.set_listing_complete(id, true)?;
+                anyhow::Ok(())
+            };
+            let listing_complete = || Ok(db.get_transfer(id)?.listing_complete);
+            let options = send_options(&transfer_args, session, rate_limit, schedule);
+            let send = client::send_pending_files(
                 path,
                 transfer.ip,
                 transfer.port,
                 &log,
                 &final_excludes,
-            )
-            .await
-            {
+                &[],
+                &options,
+                &listing_complete,
+                &shutdown,
+            );
+            let result = match scan_then_send(transfer_args.overlap, scan, send).await {
+                Ok(result) => result,
+                Err(e) if e.is::<Interrupted>() => return pause(&db, id),
+                Err(e) => return Err(e),
+            };
+            log.flush()?;
+            match result {
                 Ok(_) => {
                     db.update_status(id, "Completed")?;
                     println!("Transfer restarted and completed.");
                 }
+                Err(e) if e.is::<Interrupted>() => pause(&db, id)?,
+                Err(e) if e.is::<client::FilesFailed>() => {
+                    // Exit non-zero so scripts notice; `resume` retries them
+                    db.update_status(id, "Failed")?;
+                    return Err(e);
+                }
                 Err(e) => {
                     db.update_status(id, "Failed")?;
                     eprintln!("\nTransfer failed: {}", e);
                 }
             }
         }
+        Commands::Versions { path, action } => {
+            let base = std::fs::canonicalize(&path)?;
+            let store = versions::VersionStore::new(&base, versions::Retention::default());
+            match action {
+                VersionsAction::List { file: None } => {
+                    println!("{:<10} Path", "Versions");
+                    for (file, count) in store.list_all()? {
+                        println!("{:<10} {}", count, file);
+                    }
+                }
+                VersionsAction::List { file: Some(file) } => {
+                    let relative = paths::sanitize(&file)?;
+                    println!("{:<25} {:>15}", "Version", "Size (bytes)");
+                    for version in store.list(&relative).await? {
+                        println!("{:<25} {:>15}", version.name, version.size);
+                    }
+                }
+                VersionsAction::Restore { file, version } => {
+                    let relative = paths::sanitize(&file)?;
+                    let restored = store.restore(&relative, version.as_deref()).await?;
+                    println!("Restored {} from version {}.", file, restored.name);
+                }
+            }
+        }
+        Commands::Staging { path, action } => {
+            let staging = staging::Staging::new(&std::fs::canonicalize(&path)?);
+            match action {
+                StagingAction::List => {
+                    println!(
+                        "{:<25} {:>15} {:>8}  Path",
+                        "Session", "Size (bytes)", "Age (h)"
+                    );
+                    for partial in staging.list()? {
+                        println!(
+                            "{:<25} {:>15} {:>8}  {}",
+                            partial.session,
+                            partial.size,
+                            partial.age.as_secs() / 3600,
+                            partial.file
+                        );
+                    }
+                }
+                StagingAction::Purge {
+                    older_than,
+                    session,
+                } => {
+                    let older_than = older_than.map(|h| Duration::from_secs(h * 3600));
+                    let (files, bytes) = staging.purge(older_than, session.as_deref())?;
+                    println!("Removed {} partial file(s), {} bytes.", files, bytes);
+                }
+            }
+        }
         Commands::Remove { id } => {
             if db.get_transfer(id).is_err() {
                 eprintln!("Transfer ID {} not found.", id);
@@ -191,3 +465,101 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Runs the scan and then the send, or with `--overlap` both at once, the
+/// sender waiting on `listing_complete` before it may finish. A scan error
+/// stops everything and comes back as the outer error; otherwise the send's
+/// result is returned.
+async fn scan_then_send(
+    overlap: bool,
+    scan: impl std::future::Future<Output = Result<()>>,
+    send: impl std::future::Future<Output = Result<()>>,
+) -> Result<Result<()>> {
+    if !overlap {
+        scan.await?;
+        return Ok(send.await);
+    }
+    tokio::pin!(scan, send);
+    tokio::select! {
+        biased;
+        scanned = &mut scan => {
+            scanned?;
+            Ok(send.await)
+        }
+        // The sender only stops early on an error; the scan can be picked up
+        // again by `resume`
+        sent = &mut send => Ok(sent),
+    }
+}
+
+fn send_options(
+    args: &TransferArgs,
+    session: String,
+    rate_limit: u64,
+    schedule: Option<Schedule>,
+) -> client::SendOptions {
+    client::SendOptions {
+        retries: args.retries,
+        retry_delay: Duration::from_secs(args.retry_delay),
+        max_retry_delay: Duration::from_secs(args.max_retry_delay),
+        timeouts: net::Timeouts {
+            io: Duration::from_secs(args.timeout),
+            keepalive: Duration::from_secs(args.keepalive),
+        },
+        heartbeat: Duration::from_secs(args.heartbeat),
+        continue_on_error: args.continue_on_error,
+        on_change: args.on_change,
+        stable_for: Duration::from_secs(args.stable_for),
+        rate_limit,
+        schedule,
+        conflict: args.conflict,
+        zero_copy: !args.no_zero_copy,
+        buffers: args.buffers as usize,
+        session,
+    }
+}
+
+/// Names the transfer to the server, which keeps its partial files under it.
+/// The history id alone isn't enough, as other clients count from 1 too.
+fn session_key(transfer: &db::Transfer) -> String {
+    let created: String = transfer
+        .created_at
+        .chars()
+        .filter(|c| c.is_ascii_digit())
+        .collect();
+    format!("t{}-{}", transfer.id, created)
+}
+
+/// Picks the rate limit and schedule for a resumed transfer. Values given on
+/// the command line replace the saved ones; otherwise the saved ones apply.
+fn throttle_settings(
+    db: &Db,
+    transfer: &db::Transfer,
+    args: &TransferArgs,
+) -> Result<(u64, Option<Schedule>)> {
+    let rate_limit = args.limit.unwrap_or(transfer.rate_limit);
+    let schedule = match &args.schedule {
+        Some(schedule) => Some(schedule.clone()),
+        None => match &transfer.schedule {
+            Some(saved) => Some(saved.parse().map_err(|e| anyhow::anyhow!("{}", e))?),
+            None => None,
+        },
+    }
+    .filter(|s: &Schedule| !s.is_empty());
+
+    if args.limit.is_some() || args.schedule.is_some() {
+        db.update_throttle(
+            transfer.id,
+            rate_limit,
+            schedule.as_ref().map(|s| s.to_string()),
+        )?;
+    }
+    Ok((rate_limit, schedule))
+}
+
+/// Records a transfer the user interrupted and tells them how to continue.
+fn pause(db: &Db, id: i64) -> Result<()> {
+    db.update_status(id, "Paused")?;
+    println!("\nTransfer paused. Continue with: send resume {}", id);
+    Ok(())
+}
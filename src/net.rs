@@ -0,0 +1,46 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Socket tuning shared by client and server. A zero duration disables the
+/// corresponding timeout.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    /// Longest a single read or write on the socket may stall
+    pub io: Duration,
+    /// TCP keepalive idle time, so the OS notices peers that vanished
+    pub keepalive: Duration,
+}
+
+pub fn configure_socket(socket: &TcpStream, timeouts: &Timeouts) -> io::Result<()> {
+    socket.set_nodelay(true)?; // Disable Nagle's algorithm for lower latency
+
+    if !timeouts.keepalive.is_zero() {
+        let keepalive = TcpKeepalive::new().with_time(timeouts.keepalive);
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+        let keepalive = keepalive.with_interval(timeouts.keepalive / 3);
+        SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Runs a socket operation with a deadline, turning a stalled peer into an
+/// ordinary `TimedOut` I/O error.
+pub async fn timed<T, E: From<io::Error>>(
+    limit: Duration,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    if limit.is_zero() {
+        return fut.await;
+    }
+    match tokio::time::timeout(limit, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no activity for {}s", limit.as_secs()),
+        )
+        .into()),
+    }
+}
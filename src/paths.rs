@@ -0,0 +1,516 @@
+//! Server-side checks for paths received from clients. Paths are sanitized
+//! before being joined onto the destination root, and whatever the server
+//! writes below the root for a client goes through open directory handles,
+//! so nothing a client sends can land outside it.
+//!
+//! On Unix each directory is opened with `O_DIRECTORY | O_NOFOLLOW`
+//! relative to the one above it, creating it if needed, and files are then
+//! opened, renamed and removed relative to that handle (`openat`,
+//! `renameat`, `unlinkat`), never following a symlink in the last component
+//! either. Swapping a directory for a symlink once it has been opened
+//! redirects nothing. Checks that only look (does the file exist, how big
+//! is it) still go by path. Elsewhere directories are checked by path and
+//! used by name.
+
+use anyhow::{Result, anyhow};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+#[cfg(unix)]
+use {
+    std::ffi::{CStr, CString},
+    std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    std::os::unix::ffi::OsStrExt,
+    std::sync::Arc,
+};
+
+/// Where the server moves files replaced under the `backup` conflict policy.
+pub const BACKUP_DIR: &str = ".send-backup";
+
+/// Top-level directories the server keeps for itself; clients can't write
+/// into them.
+const RESERVED_DIRS: &[&str] = &[
+    BACKUP_DIR,
+    crate::versions::VERSIONS_DIR,
+    crate::staging::STAGING_DIR,
+];
+
+/// Device names Windows reserves in every directory, with or without an
+/// extension.
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Checks a client-supplied relative path (always `/`-separated on the wire)
+/// and turns it into a path that is safe to join onto the destination root.
+pub fn sanitize(relative_path: &str) -> Result<PathBuf> {
+    if relative_path.contains('\0') {
+        return Err(anyhow!("contains a NUL byte"));
+    }
+    // Clients normalise separators, so a backslash can only be an attempt to
+    // smuggle in a Windows separator or UNC prefix.
+    if relative_path.contains('\\') {
+        return Err(anyhow!("contains a backslash"));
+    }
+    if relative_path.starts_with('/') {
+        return Err(anyhow!("is absolute"));
+    }
+
+    let mut clean = PathBuf::new();
+    for (i, part) in relative_path.split('/').enumerate() {
+        match part {
+            "" | "." => continue,
+            ".." => return Err(anyhow!("refers to a parent directory")),
+            _ => {}
+        }
+        if i == 0 && is_drive_letter(part) {
+            return Err(anyhow!("starts with a drive letter"));
+        }
+        if clean.as_os_str().is_empty()
+            && RESERVED_DIRS
+                .iter()
+                .any(|dir| dir.eq_ignore_ascii_case(part))
+        {
+            return Err(anyhow!("uses the reserved directory {:?}", part));
+        }
+        // On every platform: the tree may be copied on to Windows later
+        check_windows_name(part)?;
+        clean.push(part);
+    }
+
+    if clean.as_os_str().is_empty() {
+        return Err(anyhow!("is empty"));
+    }
+    // Belt and braces: whatever the platform parser makes of it, only plain
+    // names may remain.
+    if !clean
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!("is not a plain relative path"));
+    }
+    Ok(clean)
+}
+
+fn is_drive_letter(part: &str) -> bool {
+    let bytes = part.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+fn check_windows_name(part: &str) -> Result<()> {
+    if part.contains(':') {
+        return Err(anyhow!("contains ':' (alternate data stream)"));
+    }
+    if part.ends_with('.') || part.ends_with(' ') {
+        return Err(anyhow!("has a name ending in a dot or space"));
+    }
+    let stem = part.split('.').next().unwrap_or(part).trim_end();
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+    {
+        return Err(anyhow!("uses the reserved name {:?}", part));
+    }
+    Ok(())
+}
+
+/// A directory under the destination root, held open. Cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Dir {
+    path: PathBuf,
+    #[cfg(unix)]
+    fd: Arc<OwnedFd>,
+}
+
+/// A name in an open directory: a file that is there or is going to be.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub dir: Dir,
+    pub name: OsString,
+}
+
+/// How `Entry::open` opens a file. A symlink is never followed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Write to a file that already exists, from the start
+    Write,
+    /// Create the file, or empty it if it is there
+    Create,
+}
+
+/// Creates `relative` (a sanitized path) under `base` one component at a
+/// time, refusing to go through anything that is a symlink or not a
+/// directory, and returns the last one opened.
+pub async fn create_dirs(base: &Path, relative: &Path) -> Result<Dir> {
+    let (base, relative) = (base.to_path_buf(), relative.to_path_buf());
+    tokio::task::spawn_blocking(move || walk(&base, &relative)).await?
+}
+
+/// Creates the directories leading up to a file, returning its parent.
+pub async fn create_parent_dirs(base: &Path, relative: &Path) -> Result<Dir> {
+    create_dirs(base, relative.parent().unwrap_or(Path::new(""))).await
+}
+
+/// Keeps the last directory `create_dirs` opened, so a run of files in one
+/// directory shares a handle instead of each holding its own while a batch
+/// waits. It is opened afresh if it is no longer at its path.
+#[derive(Default)]
+pub struct DirCache(std::sync::Mutex<Option<(PathBuf, Dir)>>);
+
+impl DirCache {
+    pub async fn create_dirs(&self, base: &Path, relative: &Path) -> Result<Dir> {
+        let cached = self.0.lock().unwrap().clone();
+        if let Some((path, dir)) = cached
+            && path == relative
+            && tokio::fs::symlink_metadata(dir.path())
+                .await
+                .is_ok_and(|meta| meta.is_dir())
+        {
+            return Ok(dir);
+        }
+        let dir = create_dirs(base, relative).await?;
+        *self.0.lock().unwrap() = Some((relative.to_path_buf(), dir.clone()));
+        Ok(dir)
+    }
+
+    pub async fn create_parent_dirs(&self, base: &Path, relative: &Path) -> Result<Dir> {
+        self.create_dirs(base, relative.parent().unwrap_or(Path::new("")))
+            .await
+    }
+}
+
+#[cfg(unix)]
+fn walk(base: &Path, relative: &Path) -> Result<Dir> {
+    // The root itself is the operator's choice and may be reached through
+    // symlinks; only what is below it is held to no-follow
+    let root = c_name(base.as_os_str())?;
+    // SAFETY: `root` is NUL-terminated; the result is checked before use
+    let fd = unsafe { libc::open(root.as_ptr(), libc::O_DIRECTORY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: `fd` was just opened and nothing else owns it
+    let mut fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut path = base.to_path_buf();
+    for part in relative.components() {
+        let name = c_name(part.as_os_str())?;
+        path.push(part);
+        let opened = match open_at(fd.as_raw_fd(), &name, libc::O_DIRECTORY) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // SAFETY: as above; `fd` is open for the call
+                if unsafe { libc::mkdirat(fd.as_raw_fd(), name.as_ptr(), 0o777) } < 0 {
+                    let e = io::Error::last_os_error();
+                    // Another session created it first
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(e.into());
+                    }
+                }
+                open_at(fd.as_raw_fd(), &name, libc::O_DIRECTORY)
+            }
+            opened => opened,
+        };
+        fd = match opened {
+            Ok(fd) => fd,
+            Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+                return Err(anyhow!("{:?} is a symlink", path));
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {
+                return Err(anyhow!("{:?} is not a directory", path));
+            }
+            Err(e) => return Err(e.into()),
+        };
+    }
+    Ok(Dir {
+        path,
+        fd: Arc::new(fd),
+    })
+}
+
+#[cfg(not(unix))]
+fn walk(base: &Path, relative: &Path) -> Result<Dir> {
+    let mut current = base.to_path_buf();
+    for part in relative.components() {
+        current.push(part);
+        match std::fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(anyhow!("{:?} is a symlink", current));
+            }
+            Ok(meta) if !meta.is_dir() => {
+                return Err(anyhow!("{:?} is not a directory", current));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match std::fs::create_dir(&current) {
+                Ok(()) => {}
+                // Another session created it first
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Dir { path: current })
+}
+
+#[cfg(unix)]
+fn c_name(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+/// `openat` that never follows a symlink in `name`.
+#[cfg(unix)]
+fn open_at(dir: RawFd, name: &CStr, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    // SAFETY: `name` is NUL-terminated and `dir` is open for the call
+    let fd = unsafe { libc::openat(dir, name.as_ptr(), flags, 0o666 as libc::c_uint) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+impl Dir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entry(&self, name: impl Into<OsString>) -> Entry {
+        Entry {
+            dir: self.clone(),
+            name: name.into(),
+        }
+    }
+
+    /// Flushes changes to the directory's entries (renames) to disk.
+    #[cfg(unix)]
+    pub async fn sync(&self) -> io::Result<()> {
+        let dir = std::fs::File::from(self.fd.try_clone()?);
+        tokio::task::spawn_blocking(move || dir.sync_all()).await?
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Dir {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Entry {
+    pub fn path(&self) -> PathBuf {
+        self.dir.path.join(&self.name)
+    }
+
+    #[cfg(unix)]
+    pub async fn open(&self, access: Access) -> io::Result<File> {
+        let flags = match access {
+            Access::Read => libc::O_RDONLY,
+            Access::Write => libc::O_WRONLY,
+            Access::Create => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+        };
+        let (dir, name) = (self.dir.fd.clone(), c_name(&self.name)?);
+        let fd =
+            tokio::task::spawn_blocking(move || open_at(dir.as_raw_fd(), &name, flags)).await??;
+        Ok(File::from_std(fd.into()))
+    }
+
+    #[cfg(not(unix))]
+    pub async fn open(&self, access: Access) -> io::Result<File> {
+        let mut options = tokio::fs::OpenOptions::new();
+        match access {
+            Access::Read => options.read(true),
+            Access::Write => options.write(true),
+            Access::Create => options.write(true).create(true).truncate(true),
+        };
+        options.open(self.path()).await
+    }
+
+    /// Moves the file to `to`, replacing whatever file is there.
+    #[cfg(unix)]
+    pub async fn rename(&self, to: &Entry) -> io::Result<()> {
+        let (from_dir, from) = (self.dir.fd.clone(), c_name(&self.name)?);
+        let (to_dir, to) = (to.dir.fd.clone(), c_name(&to.name)?);
+        tokio::task::spawn_blocking(move || {
+            // SAFETY: both names are NUL-terminated and both directories
+            // are open for the call
+            let ret = unsafe {
+                libc::renameat(
+                    from_dir.as_raw_fd(),
+                    from.as_ptr(),
+                    to_dir.as_raw_fd(),
+                    to.as_ptr(),
+                )
+            };
+            match ret {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        })
+        .await?
+    }
+
+    #[cfg(not(unix))]
+    pub async fn rename(&self, to: &Entry) -> io::Result<()> {
+        tokio::fs::rename(self.path(), to.path()).await
+    }
+
+    #[cfg(unix)]
+    pub async fn remove(&self) -> io::Result<()> {
+        let (dir, name) = (self.dir.fd.clone(), c_name(&self.name)?);
+        tokio::task::spawn_blocking(move || {
+            // SAFETY: `name` is NUL-terminated and `dir` is open for the call
+            match unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        })
+        .await?
+    }
+
+    #[cfg(not(unix))]
+    pub async fn remove(&self) -> io::Result<()> {
+        tokio::fs::remove_file(self.path()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejects(path: &str) -> bool {
+        sanitize(path).is_err()
+    }
+
+    #[test]
+    fn plain_paths_are_kept() {
+        assert_eq!(
+            sanitize("a/b.txt").unwrap(),
+            PathBuf::from("a").join("b.txt")
+        );
+        assert_eq!(sanitize("./a//b/").unwrap(), PathBuf::from("a").join("b"));
+    }
+
+    #[test]
+    fn absolute_and_parent_paths_are_rejected() {
+        assert!(rejects("/etc/passwd"));
+        assert!(rejects(".."));
+        assert!(rejects("a/../../b"));
+        assert!(rejects("a/.."));
+    }
+
+    #[test]
+    fn windows_forms_are_rejected() {
+        assert!(rejects("C:"));
+        assert!(rejects("c:/Windows/x"));
+        assert!(rejects("a\\..\\b"));
+        assert!(rejects("\\\\server\\share"));
+    }
+
+    #[test]
+    fn names_windows_reserves_are_rejected() {
+        assert!(rejects("CON"));
+        assert!(rejects("dir/aux.txt"));
+        assert!(rejects("Com1.log"));
+        assert!(rejects("a:b"));
+        assert!(rejects("name."));
+        assert!(rejects("dir/name /x"));
+        assert!(sanitize("console.txt").is_ok());
+        assert!(sanitize("dir/.hidden").is_ok());
+    }
+
+    #[test]
+    fn nul_and_empty_paths_are_rejected() {
+        assert!(rejects("a\0b"));
+        assert!(rejects(""));
+        assert!(rejects("./"));
+    }
+
+    #[test]
+    fn reserved_dirs_are_only_reserved_at_the_top() {
+        assert!(rejects(".send-staging/x"));
+        assert!(rejects(".SEND-BACKUP/x"));
+        assert!(rejects("./.send-versions"));
+        assert!(sanitize("sub/.send-staging/x").is_ok());
+    }
+
+    /// A fresh directory under the system temp dir.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("send-paths-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn create_dirs_makes_missing_dirs() {
+        let base = scratch("create");
+        create_dirs(&base, Path::new("a/b/c")).await.unwrap();
+        assert!(base.join("a/b/c").is_dir());
+        create_parent_dirs(&base, Path::new("d/file.txt"))
+            .await
+            .unwrap();
+        assert!(base.join("d").is_dir());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn create_dirs_refuses_a_symlinked_parent() {
+        let base = scratch("symlink");
+        let outside = scratch("symlink-outside");
+        std::os::unix::fs::symlink(&outside, base.join("link")).unwrap();
+        assert!(create_dirs(&base, Path::new("link/sub")).await.is_err());
+        assert!(!outside.join("sub").exists());
+        std::fs::remove_dir_all(&base).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn entries_refuse_a_symlinked_file() {
+        let base = scratch("nofollow");
+        std::fs::write(base.join("target"), b"x").unwrap();
+        std::os::unix::fs::symlink(base.join("target"), base.join("link")).unwrap();
+        let dir = create_dirs(&base, Path::new("")).await.unwrap();
+        assert!(dir.entry("link").open(Access::Create).await.is_err());
+        assert!(dir.entry("link").open(Access::Write).await.is_err());
+        assert_eq!(std::fs::read(base.join("target")).unwrap(), b"x");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn an_open_dir_is_not_redirected_by_a_swapped_symlink() {
+        let base = scratch("swap");
+        let outside = scratch("swap-outside");
+        let dir = create_dirs(&base, Path::new("a/b")).await.unwrap();
+        let staged = create_dirs(&base, Path::new("staging")).await.unwrap();
+        std::fs::write(base.join("staging/part"), b"data").unwrap();
+
+        // Someone on the server swaps `a` for a link out of the root
+        std::fs::rename(base.join("a"), base.join("moved")).unwrap();
+        std::fs::create_dir(outside.join("b")).unwrap();
+        std::os::unix::fs::symlink(&outside, base.join("a")).unwrap();
+
+        dir.entry("new").open(Access::Create).await.unwrap();
+        staged
+            .entry("part")
+            .rename(&dir.entry("file"))
+            .await
+            .unwrap();
+        assert!(base.join("moved/b/new").is_file());
+        assert_eq!(std::fs::read(base.join("moved/b/file")).unwrap(), b"data");
+        assert_eq!(std::fs::read_dir(outside.join("b")).unwrap().count(), 0);
+
+        dir.entry("new").remove().await.unwrap();
+        assert!(!base.join("moved/b/new").exists());
+        // Walking the path again finds the link
+        assert!(create_dirs(&base, Path::new("a/b")).await.is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
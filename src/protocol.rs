@@ -5,12 +5,154 @@ pub struct FileMetadata {
     pub relative_path: String,
     pub size: u64,
     pub is_dir: bool,
+    /// Modification time in seconds since the Unix epoch, if known
+    #[serde(default)]
+    pub mtime: Option<i64>,
+}
+
+/// What the server does when a file already exists at the destination with
+/// a different size, or older than the one being sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Replace the existing file
+    Overwrite,
+    /// Leave the existing file alone and skip this one
+    SkipExisting,
+    /// Replace the existing file only if ours was modified more recently
+    OverwriteIfNewer,
+    /// Store ours next to it as `name (1).ext`, `name (2).ext`, ...
+    KeepBoth,
+    /// Move the existing file into `.send-backup/<timestamp>/` first
+    Backup,
+}
+
+impl ConflictPolicy {
+    const NAMES: &[(&str, ConflictPolicy)] = &[
+        ("overwrite", ConflictPolicy::Overwrite),
+        ("skip-existing", ConflictPolicy::SkipExisting),
+        ("overwrite-if-newer", ConflictPolicy::OverwriteIfNewer),
+        ("keep-both", ConflictPolicy::KeepBoth),
+        ("backup", ConflictPolicy::Backup),
+    ];
+}
+
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, _) = Self::NAMES.iter().find(|(_, p)| p == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s.trim()))
+            .map(|(_, policy)| *policy)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::NAMES.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown policy {:?} (expected one of: {})",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Sent by the client when a session starts.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Hello {
+    /// The client's preferred conflict policy; a policy set on the server wins
+    #[serde(default)]
+    pub conflict: Option<ConflictPolicy>,
+    /// Stable key for the transfer, so partial files from one client are
+    /// kept apart from another's and found again after a reconnect
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Bytes the client still has to send, for the server's disk space check
+    #[serde(default)]
+    pub total_bytes: Option<u64>,
+    /// The client wants `Stored` notices and only counts a file as sent
+    /// once one covers it
+    #[serde(default)]
+    pub acks: bool,
+}
+
+/// Lets the client tell failures it can work around from ones that will
+/// keep happening until someone acts on the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorCategory {
+    #[default]
+    Other,
+    /// The destination disk is full or the quota is used up
+    OutOfSpace,
+}
+
+/// Messages from the client. Framing and type tags live in `codec`.
+#[derive(Debug)]
+pub enum ClientMessage {
+    Hello(Hello),
+    File(FileMetadata),
+    /// Heartbeat sent while the client has nothing else to say
+    Ping,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerResponse {
     Send,
     Skip,
-    Resume { offset: u64 },
-    Error { message: String },
+    Resume {
+        offset: u64,
+    },
+    Error {
+        message: String,
+        #[serde(default)]
+        category: ErrorCategory,
+        /// The file the error is about, when it isn't the one just offered
+        /// (e.g. running out of space while storing the previous file)
+        #[serde(default)]
+        relative_path: Option<String>,
+    },
+    Pong,
+    /// Reply to `Hello` with the settings the session will use
+    Welcome {
+        conflict: ConflictPolicy,
+        /// Something the user should know before the transfer starts
+        #[serde(default)]
+        warning: Option<String>,
+        /// `Stored` notices will follow, as the client asked
+        #[serde(default)]
+        acks: bool,
+    },
+    /// Sent ahead of the next reply to a client that asked for acks: the
+    /// oldest `files` files it sent data for and hasn't heard about yet are
+    /// now stored as safely as the server's durability setting makes them
+    Stored {
+        files: u64,
+    },
+    /// Sent ahead of the reply to a file that already existed, saying how
+    /// the conflict was resolved
+    Conflict {
+        message: String,
+    },
+    /// The server won't serve this connection (busy, not allowed, over
+    /// quota). `retry` says whether trying again later may succeed.
+    Rejected {
+        message: String,
+        retry: bool,
+    },
+}
+
+impl ServerResponse {
+    pub fn error(message: String) -> Self {
+        ServerResponse::Error {
+            message,
+            category: ErrorCategory::Other,
+            relative_path: None,
+        }
+    }
 }
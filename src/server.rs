@@ -1,176 +1,787 @@
-use crate::protocol::{FileMetadata, ServerResponse};
+use crate::access::AccessList;
+use crate::codec::{self, FrameError};
+use crate::durable::{self, Durability};
+use crate::net::{self, Timeouts};
+use crate::paths::{self, Access, Entry};
+use crate::protocol::{ClientMessage, ConflictPolicy, ErrorCategory, FileMetadata, ServerResponse};
+use crate::shutdown::Shutdown;
+use crate::staging::{STAGING_DIR, Staging};
+use crate::throttle::RateLimiter;
+use crate::uring::{IoBackend, Ring};
+use crate::versions::{Retention, VersionStore};
+use crate::walk::unix_mtime;
+use crate::zerocopy::{self, Splicer};
 use anyhow::Result;
-use std::path::{Component, PathBuf};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use tokio::fs::{self, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{Semaphore, mpsc};
 
-pub async fn run_server(base_path: PathBuf, port: u16) -> Result<()> {
+#[derive(Clone)]
+pub struct ServerOptions {
+    pub timeouts: Timeouts,
+    /// How long a client may stay silent between files before it is dropped
+    pub idle_timeout: Duration,
+    /// Address to listen on (`0.0.0.0`, `::`, or one interface's address)
+    pub bind: IpAddr,
+    /// Network interface to bind to (Linux only)
+    pub interface: Option<String>,
+    /// Most sessions served at once; 0 means no limit
+    pub max_sessions: usize,
+    pub access: AccessList,
+    /// Bandwidth cap per client address, in bytes per second
+    pub client_rate: Option<u64>,
+    /// Most files accepted from one client address while the server runs
+    pub client_max_files: Option<u64>,
+    /// Conflict policy set by the server operator; overrides the client's
+    pub conflict: Option<ConflictPolicy>,
+    /// Keep replaced files in the version store, pruned by this retention
+    pub versions: Option<Retention>,
+    /// Partial files untouched for this long are deleted (zero keeps them)
+    pub stale_partials: Duration,
+    pub durability: Durability,
+    pub space_check: SpaceCheck,
+    /// Reserve disk space for each file before writing it
+    pub preallocate: bool,
+    /// Splice data from the socket straight into files where the OS allows
+    pub zero_copy: bool,
+    /// How many buffers of received data may wait for the disk
+    pub buffers: usize,
+    pub io_backend: IoBackend,
+}
+
+/// Writes buffers from the socket side of the receive loop to `file` in
+/// order, handing each back for reuse, and flushes once the sender is done.
+async fn write_behind(
+    file: &mut File,
+    mut filled: mpsc::Receiver<(Vec<u8>, usize)>,
+    free: mpsc::Sender<Vec<u8>>,
+) -> std::io::Result<()> {
+    while let Some((buf, n)) = filled.recv().await {
+        file.write_all(&buf[..n]).await?;
+        let _ = free.send(buf).await;
+    }
+    file.flush().await
+}
+
+/// Most file data read from the socket in one go (into a buffer or the pipe).
+const RECEIVE_CHUNK: usize = 1024 * 1024;
+
+/// What to do when a client announces more data than the disk has room for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SpaceCheck {
+    Off,
+    /// Tell both sides but carry on (files that already exist are skipped,
+    /// so the announced total can overstate what is needed)
+    Warn,
+    /// Turn the client away
+    Refuse,
+}
+
+/// Usage shared by every session from the same client address.
+struct ClientQuota {
+    limiter: Option<std::sync::Mutex<RateLimiter>>,
+    files: AtomicU64,
+}
+
+pub async fn run_server(
+    base_path: PathBuf,
+    port: u16,
+    mut options: ServerOptions,
+    shutdown: Shutdown,
+) -> Result<()> {
     if !base_path.exists() {
         fs::create_dir_all(&base_path).await?;
     }
+    // Resolve the root once so the symlink checks only apply below it
+    let base_path = fs::canonicalize(&base_path).await?;
+    // Some filesystems (FUSE, some network mounts) can't be spliced into.
+    // Partials are what gets spliced, so try where they are kept
+    if options.zero_copy {
+        let supported = match paths::create_dirs(&base_path, Path::new(STAGING_DIR)).await {
+            Ok(dir) => {
+                let probe = dir.entry(".send-splice-probe");
+                let supported = match probe.open(Access::Create).await {
+                    Ok(file) => zerocopy::splice_supported(&file),
+                    Err(_) => false,
+                };
+                let _ = probe.remove().await;
+                supported
+            }
+            Err(_) => false,
+        };
+        if !supported {
+            options.zero_copy = false;
+        }
+    }
+    let ring = match options.io_backend {
+        IoBackend::Tokio => None,
+        IoBackend::IoUring => match Ring::new() {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                eprintln!("io_uring unavailable ({}), using the default file I/O", e);
+                None
+            }
+        },
+    };
 
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).await?;
+    let addr = SocketAddr::new(options.bind, port);
+    let listener = bind_listener(addr, options.interface.as_deref())?;
     println!(
         "Resuming server listening on {} saving to {:?}",
         addr, base_path
     );
 
+    let session_slots = Arc::new(Semaphore::new(if options.max_sessions > 0 {
+        options.max_sessions
+    } else {
+        Semaphore::MAX_PERMITS
+    }));
+    let mut clients: HashMap<IpAddr, Arc<ClientQuota>> = HashMap::new();
+    let rejecting = Arc::new(Semaphore::new(MAX_REJECTING));
+
+    // Checked at startup and then hourly
+    let mut cleanup = tokio::time::interval(Duration::from_secs(3600));
+
+    let mut sessions = tokio::task::JoinSet::new();
     loop {
-        let (socket, _) = listener.accept().await?;
-        let base_path = base_path.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(socket, base_path).await {
-                eprintln!("Connection error: {}", e);
+        tokio::select! {
+            _ = cleanup.tick(), if !options.stale_partials.is_zero() => {
+                let staging = Staging::new(&base_path);
+                let max_age = options.stale_partials;
+                match tokio::task::spawn_blocking(move || staging.purge(Some(max_age), None)).await? {
+                    Ok((0, _)) => {}
+                    Ok((files, bytes)) => println!(
+                        "\nRemoved {} stale partial file(s) ({})",
+                        files,
+                        format_size(bytes)
+                    ),
+                    Err(e) => eprintln!("\nCould not clean up partial files: {}", e),
+                }
             }
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+                let ip = peer.ip().to_canonical();
+
+                if !options.access.permits(ip) {
+                    eprintln!("\nRejected connection from {} (not allowed)", ip);
+                    turn_away(&rejecting, socket, "Address not allowed".into(), false);
+                    continue;
+                }
+                let Ok(permit) = session_slots.clone().try_acquire_owned() else {
+                    eprintln!("\nRejected connection from {} (session limit reached)", ip);
+                    turn_away(&rejecting, socket, "Server busy, try again later".into(), true);
+                    continue;
+                };
+                let quota = clients
+                    .entry(ip)
+                    .or_insert_with(|| {
+                        Arc::new(ClientQuota {
+                            limiter: options
+                                .client_rate
+                                .filter(|rate| *rate > 0)
+                                .map(|rate| std::sync::Mutex::new(RateLimiter::new(rate))),
+                            files: AtomicU64::new(0),
+                        })
+                    })
+                    .clone();
+
+                let base_path = base_path.clone();
+                let options = options.clone();
+                let ring = ring.clone();
+                let shutdown = shutdown.clone();
+                sessions.spawn(async move {
+                    let _permit = permit;
+                    if let Err(e) =
+                        handle_connection(socket, base_path, options, ring, quota, shutdown).await
+                    {
+                        eprintln!("Connection error: {}", e);
+                    }
+                });
+            }
+            // Reap finished sessions so the set doesn't grow forever, and
+            // forget clients left without one. Those that have sent files
+            // are kept, or --client-max-files would start over
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {
+                clients.retain(|_, quota| {
+                    Arc::strong_count(quota) > 1 || quota.files.load(Ordering::Relaxed) > 0
+                });
+            }
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    // Sessions stop at their next chunk and keep their partial files
+    if !sessions.is_empty() {
+        println!(
+            "\nWaiting for {} active session(s) to save partial files...",
+            sessions.len()
+        );
+    }
+    while sessions.join_next().await.is_some() {}
+    println!("Server stopped. Clients can resume where they left off.");
+    Ok(())
+}
+
+fn bind_listener(addr: SocketAddr, interface: Option<&str>) -> Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    if let Some(interface) = interface {
+        #[cfg(target_os = "linux")]
+        socket.bind_device(Some(interface.as_bytes()))?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!(
+            "Binding to interface {:?} is only supported on Linux; use its address with --bind",
+            interface
+        ));
+    }
+    socket.bind(addr)?;
+    Ok(socket.listen(1024)?)
+}
+
+/// Most connections being turned away politely at once. Each can take a
+/// few seconds; past this, new ones are simply closed.
+const MAX_REJECTING: usize = 64;
+
+/// Rejects `socket` in the background if there is room, else closes it.
+fn turn_away(slots: &Arc<Semaphore>, socket: TcpStream, message: String, retry: bool) {
+    if let Ok(permit) = slots.clone().try_acquire_owned() {
+        tokio::spawn(async move {
+            reject(socket, message, retry).await;
+            drop(permit);
         });
     }
 }
 
-async fn handle_connection(mut socket: TcpStream, base_path: PathBuf) -> Result<()> {
-    socket.set_nodelay(true)?; // Optimize latency
+/// Tells a client why it is being turned away.
+async fn reject(mut socket: TcpStream, message: String, retry: bool) {
+    hang_up(&mut socket, ServerResponse::Rejected { message, retry }).await;
+}
+
+/// Sends a last message, then waits briefly for the client to hang up so the
+/// message isn't lost to a connection reset.
+async fn hang_up(socket: &mut TcpStream, resp: ServerResponse) {
+    let timeout = Duration::from_secs(5);
+    if send_response(socket, resp, timeout).await.is_err() {
+        return;
+    }
+    let _ = socket.shutdown().await;
+    let mut sink = [0u8; 4096];
+    let _ = tokio::time::timeout(timeout, async {
+        while matches!(socket.read(&mut sink).await, Ok(n) if n > 0) {}
+    })
+    .await;
+}
+
+async fn handle_connection(
+    mut socket: TcpStream,
+    base_path: PathBuf,
+    options: ServerOptions,
+    ring: Option<Ring>,
+    quota: Arc<ClientQuota>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    net::configure_socket(&socket, &options.timeouts)?;
+    let io_timeout = options.timeouts.io;
     let mut total_files_recvd = 0u64;
     let mut total_skipped = 0u64;
     let mut total_bytes_recvd = 0u64;
     let mut last_update = std::time::Instant::now();
     let update_interval = std::time::Duration::from_millis(300);
+    let mut conflict = options.conflict.unwrap_or(ConflictPolicy::Overwrite);
+    // One backup directory per session, so a run's replaced files stay together
+    let backup_dir =
+        Path::new(paths::BACKUP_DIR).join(chrono::Local::now().format("%Y%m%d-%H%M%S").to_string());
+
+    let staging = Staging::new(&base_path);
+    let parents = paths::DirCache::default();
+    let mut session = Staging::session_name(None);
+    let versions = options
+        .versions
+        .map(|retention| VersionStore::new(&base_path, retention));
+    // One pipe per session for zero-copy receives; without one, file data
+    // goes through buffers that are allocated on first use and kept
+    let splicer = options
+        .zero_copy
+        .then(Splicer::new)
+        .and_then(|splicer| splicer.ok());
+    let buffers = options.buffers.max(1);
+    let mut pool: Vec<Vec<u8>> = Vec::new();
 
     // Initial status
     print!("\rReceiving: Files: 0, Skipped: 0, Size: 0 B");
     let _ = std::io::Write::flush(&mut std::io::stdout());
 
-    loop {
-        // Read metadata length
-        let mut len_buf = [0u8; 4];
-        if socket.read_exact(&mut len_buf).await.is_err() {
-            break; // Client disconnected
-        }
-        let len = u32::from_be_bytes(len_buf) as usize;
-
-        // Read metadata
-        let mut meta_buf = vec![0u8; len];
-        socket.read_exact(&mut meta_buf).await?;
-        let metadata: FileMetadata = serde_json::from_slice(&meta_buf)?;
-
-        let relative_path = PathBuf::from(&metadata.relative_path);
-        if relative_path
-            .components()
-            .any(|c| matches!(c, Component::ParentDir))
-        {
-            eprintln!(
-                "Security warning: Attempt to write outside base path: {:?}",
-                metadata.relative_path
-            );
-            send_response(
-                &mut socket,
-                ServerResponse::Error {
-                    message: "Invalid path".into(),
-                },
-            )
-            .await?;
-            continue;
-        }
-
-        // println!("Receiving: {:?}", metadata.relative_path); // Removed to avoid interfering with progress bar
+    // Files already received must reach their final name however the
+    // session ends, so the loop's errors are held until the batch is flushed.
+    let mut committer = Committer {
+        durability: options.durability,
+        staging: &staging,
+        versions: versions.as_ref(),
+        ring: ring.as_ref(),
+        batch: Vec::new(),
+        batch_bytes: 0,
+        batch_started: Instant::now(),
+        stored: 0,
+    };
+    let mut acks = false;
+    let result: Result<()> = async {
+        loop {
+            // A quiet client shouldn't leave its last files waiting in the batch
+            if let Some(wait) = committer.time_left()
+                && tokio::time::timeout(wait, socket.readable()).await.is_err()
+            {
+                committer.flush().await?;
+            }
+            report_stored(&mut socket, &mut committer, acks, io_timeout).await?;
 
-        let target_path = base_path.join(&relative_path);
+            // Read the next message
+            let read = tokio::select! {
+                read = net::timed(options.idle_timeout, codec::read_client(&mut socket)) => read,
+                _ = shutdown.wait() => break,
+            };
+            let metadata = match read {
+                Ok(ClientMessage::File(metadata)) => metadata,
+                Ok(ClientMessage::Ping) => {
+                    // A client checking in may be about to leave; don't keep
+                    // what it sent waiting in the batch
+                    committer.flush().await?;
+                    report_stored(&mut socket, &mut committer, acks, io_timeout).await?;
+                    send_response(&mut socket, ServerResponse::Pong, io_timeout).await?;
+                    continue;
+                }
+                Ok(ClientMessage::Hello(hello)) => {
+                    conflict = options.conflict.or(hello.conflict).unwrap_or(conflict);
+                    session = Staging::session_name(hello.session.as_deref());
+                    acks = hello.acks;
 
-        if metadata.is_dir {
-            fs::create_dir_all(&target_path).await?;
-            send_response(&mut socket, ServerResponse::Skip).await?;
-            continue;
-        }
+                    // Better to say so now than to die hours in on a full disk
+                    let mut warning = None;
+                    if let Some(needed) = hello.total_bytes
+                        && options.space_check != SpaceCheck::Off
+                        && let Some(free) = free_space(&base_path)
+                        && needed > free
+                    {
+                        let message = format!(
+                            "Not enough free space on the server: {} to send, {} available",
+                            format_size(needed),
+                            format_size(free)
+                        );
+                        eprintln!("\n{}", message);
+                        if options.space_check == SpaceCheck::Refuse {
+                            let resp = ServerResponse::Rejected {
+                                message,
+                                retry: false,
+                            };
+                            hang_up(&mut socket, resp).await;
+                            break;
+                        }
+                        warning = Some(message);
+                    }
+                    let resp = ServerResponse::Welcome {
+                        conflict,
+                        warning,
+                        acks,
+                    };
+                    send_response(&mut socket, resp, io_timeout).await?;
+                    continue;
+                }
+                Err(FrameError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut => {
+                    eprintln!("\nClient idle for too long, closing connection.");
+                    break;
+                }
+                Err(FrameError::Io(_)) => break, // Client disconnected
+                Err(e) => {
+                    // The stream can't be trusted after a bad frame, so tell the
+                    // client why and hang up.
+                    eprintln!("\nProtocol error from client: {}", e);
+                    let message = e.to_string();
+                    let _ =
+                        send_response(&mut socket, ServerResponse::error(message), io_timeout).await;
+                    break;
+                }
+            };
 
-        // Check if file exists AND matches size
-        let skip = if target_path.exists() {
-            let meta = fs::metadata(&target_path).await?;
-            meta.len() == metadata.size
-        } else {
-            false
-        };
+            let relative_path = match paths::sanitize(&metadata.relative_path) {
+                Ok(path) => path,
+                Err(e) => {
+                    eprintln!(
+                        "\nSecurity warning: Rejected path {:?}: {}",
+                        metadata.relative_path, e
+                    );
+                    let message = format!("Invalid path: {}", e);
+                    send_response(&mut socket, ServerResponse::error(message), io_timeout).await?;
+                    continue;
+                }
+            };
 
-        if skip {
-            send_response(&mut socket, ServerResponse::Skip).await?;
-            total_skipped += 1;
-            // println!("Skipping existing: {:?}", metadata.relative_path);
-            continue;
-        }
+            // println!("Receiving: {:?}", metadata.relative_path); // Removed to avoid interfering with progress bar
 
-        let temp_path = target_path.with_file_name(format!(
-            "{}.tmp",
-            target_path.file_name().unwrap().to_string_lossy()
-        ));
-        let mut offset = 0;
-
-        let mut file = if temp_path.exists() {
-            let f = File::options().append(true).open(&temp_path).await?;
-            offset = f.metadata().await?.len();
-            if offset > metadata.size {
-                // Invalid state, start over
-                let f = File::create(&temp_path).await?;
-                offset = 0;
-                f
+            let created = if metadata.is_dir {
+                paths::create_dirs(&base_path, &relative_path).await
             } else {
-                f
-            }
-        } else {
-            if let Some(parent) = temp_path.parent() {
-                fs::create_dir_all(parent).await?;
+                parents.create_parent_dirs(&base_path, &relative_path).await
+            };
+            let parent = match created {
+                Ok(dir) => dir,
+                Err(e) => {
+                    eprintln!(
+                        "\nSecurity warning: Refusing to write {:?}: {}",
+                        metadata.relative_path, e
+                    );
+                    let message = format!("Invalid path: {}", e);
+                    send_response(&mut socket, ServerResponse::error(message), io_timeout)
+                        .await?;
+                    continue;
+                }
+            };
+
+            if metadata.is_dir {
+                send_response(&mut socket, ServerResponse::Skip, io_timeout).await?;
+                continue;
             }
-            File::create(&temp_path).await?
-        };
+            let name = relative_path.file_name().expect("sanitized paths end in a name");
+            let target = parent.entry(name);
 
-        if offset > 0 && offset < metadata.size {
-            send_response(&mut socket, ServerResponse::Resume { offset }).await?;
-            // println!("Resuming from: {}", offset);
-        } else if offset >= metadata.size {
-            // Already downloaded fully in temp?
-            file.shutdown().await?;
-            fs::rename(&temp_path, &target_path).await?;
-            send_response(&mut socket, ServerResponse::Skip).await?;
-            total_skipped += 1;
-            // println!("Restored from temp: {:?}", metadata.relative_path);
-            continue;
-        } else {
-            send_response(&mut socket, ServerResponse::Send).await?;
-        }
+            // Check if file exists AND matches. A symlink in its place is
+            // never followed; the rename below replaces the link itself.
+            let existing = fs::symlink_metadata(target.path()).await.ok();
+            let skip = existing
+                .as_ref()
+                .is_some_and(|meta| same_file(&metadata, meta));
 
-        // Receive File content with Progress Update
-        let remaining = metadata.size - offset;
-        let mut take = (&mut socket).take(remaining);
+            if skip {
+                send_response(&mut socket, ServerResponse::Skip, io_timeout).await?;
+                total_skipped += 1;
+                // println!("Skipping existing: {:?}", metadata.relative_path);
+                continue;
+            }
 
-        // Custom copy loop for progress
-        // Increased buffer size to 1MB
-        let mut buf = vec![0u8; 1024 * 1024];
-        loop {
-            let n = take.read(&mut buf).await?;
-            if n == 0 {
+            // A different file is already there: settle it with the session's policy
+            let mut target = target;
+            let mut backup = None;
+            if let Some(existing) = existing.filter(|meta| meta.is_file()) {
+                let resolution = resolve_conflict(
+                    conflict,
+                    &base_path,
+                    &relative_path,
+                    &target,
+                    &metadata,
+                    &existing,
+                    &backup_dir,
+                )
+                .await;
+                let message = match resolution {
+                    Ok(Resolution::Replace {
+                        backup: moved_to,
+                        message,
+                    }) => {
+                        backup = moved_to;
+                        message
+                    }
+                    Ok(Resolution::Rename {
+                        target: renamed,
+                        message,
+                    }) => {
+                        target = renamed;
+                        message
+                    }
+                    Ok(Resolution::Keep { message }) => {
+                        let notice = ServerResponse::Conflict {
+                            message: format!("{}: {}", metadata.relative_path, message),
+                        };
+                        send_response(&mut socket, notice, io_timeout).await?;
+                        send_response(&mut socket, ServerResponse::Skip, io_timeout).await?;
+                        total_skipped += 1;
+                        continue;
+                    }
+                    Err(e) => {
+                        let message = format!("Conflict with existing file: {}", e);
+                        send_response(&mut socket, ServerResponse::error(message), io_timeout)
+                            .await?;
+                        continue;
+                    }
+                };
+                let notice = ServerResponse::Conflict {
+                    message: format!("{}: {}", metadata.relative_path, message),
+                };
+                send_response(&mut socket, notice, io_timeout).await?;
+            }
+
+            if let Some(max) = options.client_max_files
+                && quota.files.fetch_add(1, Ordering::Relaxed) >= max
+            {
+                let resp = ServerResponse::Rejected {
+                    message: format!("File quota of {} reached", max),
+                    retry: false,
+                };
+                send_response(&mut socket, resp, io_timeout).await?;
                 break;
             }
-            file.write_all(&buf[..n]).await?;
 
-            total_bytes_recvd += n as u64;
+            // Partials are kept per session, out of the user's folders. One
+            // left by another version of the file (the source changed since)
+            // is thrown away rather than resumed
+            let version = format!(
+                "{} {}",
+                metadata.size,
+                metadata.mtime.map_or("-".to_string(), |m| m.to_string())
+            );
+            let temp = staging
+                .partial_path(&session, target.path().strip_prefix(&base_path)?, &version)
+                .await?;
+            let mut offset = 0;
+
+            // With io_uring, a file that fits in one buffer is opened,
+            // written and closed by the ring once all of it is in. It is
+            // always written from scratch, so any old partial is ignored.
+            let mut sink = if let Some(ring) = &ring
+                && metadata.size > 0
+                && metadata.size <= RECEIVE_CHUNK as u64
+            {
+                Sink::Ring(ring)
+            } else {
+                // Not append mode: splice refuses files opened with O_APPEND
+                match temp.open(Access::Write).await {
+                    Ok(mut f) => {
+                        offset = f.metadata().await?.len();
+                        if offset > metadata.size {
+                            // Invalid state, start over
+                            offset = 0;
+                            Sink::File(temp.open(Access::Create).await?)
+                        } else {
+                            f.seek(std::io::SeekFrom::Start(offset)).await?;
+                            Sink::File(f)
+                        }
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        Sink::File(temp.open(Access::Create).await?)
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            };
 
-            if last_update.elapsed() >= update_interval {
-                print!(
-                    "\rReceiving: Files: {}, Skipped: {}, Size: {} | Current: {:.30}               ",
-                    total_files_recvd,
-                    total_skipped,
-                    format_size(total_bytes_recvd),
-                    metadata.relative_path
+            // Reserving the rest of the file up front cuts fragmentation and
+            // finds a full disk now rather than part-way through. Filesystems
+            // that can't do it just get written normally.
+            if options.preallocate
+                && offset < metadata.size
+                && let Sink::File(file) = &sink
+                && let Err(e) = preallocate(file, offset, metadata.size - offset).await
+                && is_out_of_space(&e)
+            {
+                eprintln!(
+                    "\nNo room for {:?} ({}): {}",
+                    metadata.relative_path,
+                    format_size(metadata.size),
+                    e
                 );
-                let _ = std::io::Write::flush(&mut std::io::stdout());
-                last_update = std::time::Instant::now();
+                let resp = ServerResponse::Error {
+                    message: format!(
+                        "Server is out of disk space for {} ({}): {}",
+                        metadata.relative_path,
+                        format_size(metadata.size),
+                        e
+                    ),
+                    category: ErrorCategory::OutOfSpace,
+                    relative_path: Some(metadata.relative_path.clone()),
+                };
+                hang_up(&mut socket, resp).await;
+                break;
             }
-        }
 
-        file.flush().await?;
-        fs::rename(&temp_path, &target_path).await?;
+            if offset > 0 && offset < metadata.size {
+                send_response(&mut socket, ServerResponse::Resume { offset }, io_timeout).await?;
+                // println!("Resuming from: {}", offset);
+            } else if offset >= metadata.size {
+                // Already downloaded fully in temp?
+                if let Sink::File(file) = &mut sink {
+                    file.shutdown().await?;
+                }
+                let finished = Finished {
+                    temp,
+                    target,
+                    backup,
+                    mtime: metadata.mtime,
+                    acked: false,
+                };
+                committer.commit(sink, metadata.size, finished).await?;
+                send_response(&mut socket, ServerResponse::Skip, io_timeout).await?;
+                total_skipped += 1;
+                // println!("Restored from temp: {:?}", metadata.relative_path);
+                continue;
+            } else {
+                send_response(&mut socket, ServerResponse::Send, io_timeout).await?;
+            }
+
+            // Receive File content with Progress Update
+            let remaining = metadata.size - offset;
+
+            // Bytes in, the client's rate limit and the status line, for
+            // either receive path below. Returns how long to pause.
+            let mut account = |n: usize| {
+                total_bytes_recvd += n as u64;
+                if last_update.elapsed() >= update_interval {
+                    print!(
+                        "\rReceiving: Files: {}, Skipped: {}, Size: {} | Current: {:.30}               ",
+                        total_files_recvd,
+                        total_skipped,
+                        format_size(total_bytes_recvd),
+                        metadata.relative_path
+                    );
+                    let _ = std::io::Write::flush(&mut std::io::stdout());
+                    last_update = std::time::Instant::now();
+                }
+                match &quota.limiter {
+                    Some(limiter) => limiter.lock().unwrap().reserve(n as u64),
+                    None => Duration::ZERO,
+                }
+            };
+
+            let mut received = 0u64;
+            let mut write_error = None;
+            match (&mut sink, &splicer) {
+                (Sink::Ring(ring), _) => {
+                    // Small file: read it whole, then one trip through the ring
+                    let mut buf = pool.pop().unwrap_or_else(|| vec![0u8; RECEIVE_CHUNK]);
+                    while received < remaining && !shutdown.requested() {
+                        let free = &mut buf[received as usize..remaining as usize];
+                        let n = net::timed(io_timeout, socket.read(free)).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        received += n as u64;
+                        let delay = account(n);
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                    if received == remaining {
+                        let sync = options.durability == Durability::Full;
+                        let (buf_back, result) = ring
+                            .write_file(&temp, buf, remaining as usize, sync)
+                            .await;
+                        write_error = result.err();
+                        buf = buf_back;
+                    }
+                    pool.push(buf);
+                }
+                (Sink::File(file), Some(splicer)) => {
+                    // socket -> pipe -> file, never through our memory
+                    let mut position = offset;
+                    while received < remaining && !shutdown.requested() {
+                        let want = (remaining - received).min(RECEIVE_CHUNK as u64) as usize;
+                        let n = net::timed(io_timeout, splicer.fill(&socket, want)).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        // The disk write blocks; let tokio move other tasks off
+                        // this worker meanwhile
+                        let drained = tokio::task::block_in_place(|| {
+                            splicer.drain(file, &mut position, n)
+                        });
+                        if let Err(e) = drained {
+                            write_error = Some(e);
+                            break;
+                        }
+                        received += n as u64;
+                        let delay = account(n);
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                }
+                (Sink::File(file), None) => {
+                    // The socket is read into up to `buffers` buffers while
+                    // earlier ones are written out behind it
+                    let (filled_tx, filled_rx) = mpsc::channel(buffers);
+                    let (free_tx, mut free_rx) = mpsc::channel(buffers);
+                    let reading = async {
+                        let filled_tx = filled_tx; // dropped on return, ending the writer
+                        let mut in_use = 0;
+                        while received < remaining && !shutdown.requested() {
+                            let mut buf = if in_use < buffers {
+                                in_use += 1;
+                                pool.pop().unwrap_or_else(|| vec![0u8; RECEIVE_CHUNK])
+                            } else {
+                                match free_rx.recv().await {
+                                    Some(buf) => buf,
+                                    None => break, // the writer failed
+                                }
+                            };
+                            let want = (remaining - received).min(buf.len() as u64) as usize;
+                            let n = net::timed(io_timeout, socket.read(&mut buf[..want])).await?;
+                            if n == 0 || filled_tx.send((buf, n)).await.is_err() {
+                                break;
+                            }
+                            received += n as u64;
+                            let delay = account(n);
+                            if !delay.is_zero() {
+                                tokio::time::sleep(delay).await;
+                            }
+                        }
+                        Ok::<_, std::io::Error>(())
+                    };
+                    let (read, written) =
+                        tokio::join!(reading, write_behind(file, filled_rx, free_tx));
+                    read?;
+                    write_error = written.err();
+                    while let Ok(buf) = free_rx.try_recv() {
+                        pool.push(buf);
+                    }
+                }
+            }
+
+            if let Some(e) = write_error {
+                if !is_out_of_space(&e) {
+                    return Err(e.into());
+                }
+                // Keep the partial; the client can resume once space is freed
+                eprintln!(
+                    "\nOut of disk space writing {:?}: {}",
+                    metadata.relative_path, e
+                );
+                let resp = ServerResponse::Error {
+                    message: format!("Server is out of disk space: {}", e),
+                    category: ErrorCategory::OutOfSpace,
+                    relative_path: Some(metadata.relative_path.clone()),
+                };
+                hang_up(&mut socket, resp).await;
+                break;
+            }
+            if received < remaining {
+                // Client went away or we are shutting down: keep the partial
+                // file so the next session can resume it.
+                break;
+            }
+            let finished = Finished {
+                temp,
+                target,
+                backup,
+                mtime: metadata.mtime,
+                acked: true,
+            };
+            committer.commit(sink, metadata.size, finished).await?;
 
-        total_files_recvd += 1;
-        // println!("Finished: {:?}", metadata.relative_path);
+            total_files_recvd += 1;
+            // println!("Finished: {:?}", metadata.relative_path);
+        }
+        Ok(())
     }
+    .await;
+    committer.flush().await?;
+    result?;
+
     println!(
         "\rDone! Total Files: {}, Skipped: {}, Total Size: {}                                    ",
         total_files_recvd,
@@ -180,6 +791,337 @@ async fn handle_connection(mut socket: TcpStream, base_path: PathBuf) -> Result<
     Ok(())
 }
 
+enum Resolution {
+    /// Write over the existing file, moving it to `backup` first if set
+    Replace {
+        backup: Option<Entry>,
+        message: String,
+    },
+    /// Write to a different path instead
+    Rename { target: Entry, message: String },
+    /// Leave the existing file and skip this one
+    Keep { message: String },
+}
+
+/// Decides what happens to an incoming file when a different regular file
+/// (see `same_file`) already exists at its destination.
+async fn resolve_conflict(
+    policy: ConflictPolicy,
+    base_path: &Path,
+    relative_path: &Path,
+    target: &Entry,
+    incoming: &FileMetadata,
+    existing: &std::fs::Metadata,
+    backup_dir: &Path,
+) -> Result<Resolution> {
+    let resolution = match policy {
+        ConflictPolicy::Overwrite => Resolution::Replace {
+            backup: None,
+            message: "replacing existing file".into(),
+        },
+        ConflictPolicy::SkipExisting => Resolution::Keep {
+            message: "kept existing file".into(),
+        },
+        ConflictPolicy::OverwriteIfNewer => match (incoming.mtime, unix_mtime(existing)) {
+            (Some(ours), Some(theirs)) if ours > theirs => Resolution::Replace {
+                backup: None,
+                message: "replacing older existing file".into(),
+            },
+            (Some(_), Some(_)) => Resolution::Keep {
+                message: "kept existing file (same age or newer)".into(),
+            },
+            _ => Resolution::Keep {
+                message: "kept existing file (modification times unknown)".into(),
+            },
+        },
+        ConflictPolicy::KeepBoth => {
+            // First free `name (n).ext`; one that already holds the same file
+            // is taken to be this file from an earlier run.
+            let mut n = 1;
+            loop {
+                let candidate = numbered(relative_path, n);
+                match fs::symlink_metadata(base_path.join(&candidate)).await {
+                    Ok(meta) if same_file(incoming, &meta) => {
+                        break Resolution::Keep {
+                            message: format!("already kept as {}", candidate.display()),
+                        };
+                    }
+                    Ok(_) => n += 1,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        let name = candidate.file_name().expect("numbered keeps the name");
+                        break Resolution::Rename {
+                            target: target.dir.entry(name),
+                            message: format!(
+                                "existing file kept, saving as {}",
+                                candidate.display()
+                            ),
+                        };
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        ConflictPolicy::Backup => {
+            let backup = backup_dir.join(relative_path);
+            let dir = paths::create_parent_dirs(base_path, &backup).await?;
+            Resolution::Replace {
+                message: format!("existing file moved to {}", backup.display()),
+                backup: Some(dir.entry(&target.name)),
+            }
+        }
+    };
+    Ok(resolution)
+}
+
+/// Whether `existing` already holds the incoming file: the same size, and
+/// not older than it where both modification times are known. Received
+/// files get the sender's mtime, so a source edited since shows up as newer.
+fn same_file(incoming: &FileMetadata, existing: &std::fs::Metadata) -> bool {
+    let newer = matches!(
+        (incoming.mtime, unix_mtime(existing)),
+        (Some(ours), Some(theirs)) if ours > theirs
+    );
+    existing.is_file() && existing.len() == incoming.size && !newer
+}
+
+/// `dir/name.ext` -> `dir/name (n).ext`
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// A received file waiting to take its final name.
+struct Finished {
+    temp: Entry,
+    target: Entry,
+    backup: Option<Entry>,
+    /// The sender's modification time, given to the file before it moves
+    mtime: Option<i64>,
+    /// The client sent the data and waits to hear the file is stored; not
+    /// set for a finished partial it was told to skip
+    acked: bool,
+}
+
+/// Most files or bytes a batch holds before it is synced and renamed.
+const BATCH_FILES: usize = 256;
+const BATCH_BYTES: u64 = 64 * 1024 * 1024;
+/// How long a batch may wait for more files while the client is quiet.
+const BATCH_WINDOW: Duration = Duration::from_secs(1);
+
+/// Where a file being received is written: an open partial, or for small
+/// files, the ring, which gets the whole file in one go.
+enum Sink<'a> {
+    File(File),
+    Ring(&'a Ring),
+}
+
+/// Moves finished files into place, syncing them first as `durability` asks.
+struct Committer<'a> {
+    durability: Durability,
+    staging: &'a Staging,
+    versions: Option<&'a VersionStore>,
+    ring: Option<&'a Ring>,
+    batch: Vec<Finished>,
+    batch_bytes: u64,
+    batch_started: Instant,
+    /// Files moved into place that the client hasn't been told about
+    stored: u64,
+}
+
+impl Committer<'_> {
+    async fn commit(&mut self, sink: Sink<'_>, size: u64, finished: Finished) -> Result<()> {
+        match self.durability {
+            Durability::Off => {
+                drop(sink);
+                self.apply(&finished).await?;
+                self.stored += finished.acked as u64;
+                Ok(())
+            }
+            Durability::Full => {
+                // The ring syncs its files before closing them
+                if let Sink::File(file) = &sink {
+                    file.sync_all().await?;
+                }
+                drop(sink);
+                self.apply(&finished).await?;
+                durable::sync_dir(&finished.target.dir).await?;
+                self.stored += finished.acked as u64;
+                Ok(())
+            }
+            Durability::Batch => {
+                drop(sink);
+                if self.batch.is_empty() {
+                    self.batch_started = Instant::now();
+                }
+                self.batch.push(finished);
+                self.batch_bytes += size;
+                if self.batch.len() >= BATCH_FILES || self.batch_bytes >= BATCH_BYTES {
+                    self.flush().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// How much longer the current batch may wait, if there is one.
+    fn time_left(&self) -> Option<Duration> {
+        (!self.batch.is_empty()).then(|| BATCH_WINDOW.saturating_sub(self.batch_started.elapsed()))
+    }
+
+    /// Syncs the batch's data, renames every file, then syncs the
+    /// directories they landed in.
+    async fn flush(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let temps = self.batch.iter().map(|f| f.temp.clone()).collect();
+        durable::sync_files(temps).await?;
+
+        let mut dirs = std::collections::BTreeMap::new();
+        let mut acked = 0;
+        for finished in std::mem::take(&mut self.batch) {
+            self.apply(&finished).await?;
+            let dir = &finished.target.dir;
+            dirs.entry(dir.path().to_path_buf())
+                .or_insert_with(|| dir.clone());
+            acked += finished.acked as u64;
+        }
+        self.batch_bytes = 0;
+        for dir in dirs.values() {
+            durable::sync_dir(dir).await?;
+        }
+        self.stored += acked;
+        Ok(())
+    }
+
+    async fn apply(&self, finished: &Finished) -> Result<()> {
+        if let Some(mtime) = finished.mtime {
+            set_mtime(&finished.temp, mtime).await;
+        }
+        finish_file(
+            &finished.temp,
+            &finished.target,
+            finished.backup.as_ref(),
+            self.versions,
+            self.ring,
+        )
+        .await?;
+        self.staging.finished(&finished.temp).await;
+        Ok(())
+    }
+}
+
+/// Tells a client that asked for acks about the files stored since it last
+/// heard, so it can count them as sent.
+async fn report_stored(
+    socket: &mut TcpStream,
+    committer: &mut Committer<'_>,
+    acks: bool,
+    timeout: Duration,
+) -> Result<()> {
+    if !acks || committer.stored == 0 {
+        return Ok(());
+    }
+    let files = std::mem::take(&mut committer.stored);
+    send_response(socket, ServerResponse::Stored { files }, timeout).await
+}
+
+/// Moves a completed temp file into place. Whatever is there goes to
+/// `backup` when the backup policy asked for it, or else into the version
+/// store if the server keeps versions.
+async fn finish_file(
+    temp: &Entry,
+    target: &Entry,
+    backup: Option<&Entry>,
+    versions: Option<&VersionStore>,
+    ring: Option<&Ring>,
+) -> Result<()> {
+    match (fs::symlink_metadata(target.path()).await, backup, versions) {
+        (Ok(_), Some(backup), _) => target.rename(backup).await?,
+        (Ok(meta), None, Some(versions)) if meta.is_file() => {
+            versions.save(target).await?;
+        }
+        _ => {}
+    }
+    match ring {
+        Some(ring) => ring.rename(temp, target).await?,
+        None => temp.rename(target).await?,
+    }
+    Ok(())
+}
+
+/// Best effort: a file left with the time it was received just looks newer
+/// than its source, which `same_file` still takes as a match.
+async fn set_mtime(file: &Entry, secs: i64) {
+    let Ok(secs) = u64::try_from(secs) else {
+        return;
+    };
+    let time = std::time::UNIX_EPOCH + Duration::from_secs(secs);
+    let Ok(file) = file.open(Access::Write).await else {
+        return;
+    };
+    let file = file.into_std().await;
+    let _ = tokio::task::spawn_blocking(move || file.set_modified(time)).await;
+}
+
+/// Allocates `len` bytes of disk from `offset` on without changing the file's
+/// size, which is what resuming goes by.
+#[cfg(target_os = "linux")]
+async fn preallocate(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let file = file.try_clone().await?.into_std().await;
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: the descriptor stays open for the duration of the call
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    })
+    .await?
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn preallocate(_file: &File, _offset: u64, _len: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statvfs is plain old data, and the call only writes into it
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn is_out_of_space(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+    )
+}
+
 fn format_size(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
@@ -196,10 +1138,11 @@ fn format_size(bytes: u64) -> String {
     }
 }
 
-async fn send_response(socket: &mut TcpStream, resp: ServerResponse) -> Result<()> {
-    let json = serde_json::to_vec(&resp)?;
-    let len = (json.len() as u32).to_be_bytes();
-    socket.write_all(&len).await?;
-    socket.write_all(&json).await?;
+async fn send_response(
+    socket: &mut TcpStream,
+    resp: ServerResponse,
+    timeout: Duration,
+) -> Result<()> {
+    net::timed(timeout, codec::write_server(socket, &resp)).await?;
     Ok(())
 }
@@ -0,0 +1,249 @@
+//! Partial files on the server. They live in `.send-staging/<session>/` under
+//! the destination root, named by a hash of their destination path, so they
+//! never mix with the user's own files. A `<hash>.path` note next to each one
+//! records where it is going, for `send staging list`, and on a second line
+//! which version of the file it holds.
+
+use crate::paths::{Access, DirCache, Entry};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+pub const STAGING_DIR: &str = ".send-staging";
+
+/// Session used by clients that don't name one.
+const DEFAULT_SESSION: &str = "default";
+
+pub struct Partial {
+    pub session: String,
+    /// Destination path, or the hash if the note is missing
+    pub file: String,
+    pub size: u64,
+    pub age: Duration,
+    path: PathBuf,
+}
+
+pub struct Staging {
+    base: PathBuf,
+    session_dir: DirCache,
+}
+
+impl Staging {
+    pub fn new(base: &Path) -> Self {
+        Staging {
+            base: base.to_path_buf(),
+            session_dir: DirCache::default(),
+        }
+    }
+
+    /// Turns a client-chosen session key into a safe directory name.
+    pub fn session_name(key: Option<&str>) -> String {
+        match key {
+            Some(key)
+                if !key.is_empty()
+                    && key.len() <= 64
+                    && key
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                key.to_string()
+            }
+            _ => DEFAULT_SESSION.to_string(),
+        }
+    }
+
+    /// Where the partial for `relative` (a sanitized destination path) is
+    /// kept in `session`. Creates the session directory and the path note.
+    /// `version` identifies the file being received (its size and mtime); a
+    /// partial left by a different version is deleted, since resuming it
+    /// would join two files together. Notes from before versions were
+    /// recorded are taken to match.
+    pub async fn partial_path(
+        &self,
+        session: &str,
+        relative: &Path,
+        version: &str,
+    ) -> Result<Entry> {
+        let dir = self
+            .session_dir
+            .create_dirs(&self.base, &Path::new(STAGING_DIR).join(session))
+            .await?;
+        let name = format!("{:016x}", fnv1a(relative.to_string_lossy().as_bytes()));
+        let partial = dir.entry(&name);
+        let note = note_entry(&partial);
+        let noted = match read_note(&note).await {
+            Ok(text) => Some(text.lines().nth(1).map(str::to_string)),
+            Err(_) => None,
+        };
+        if let Some(Some(noted)) = &noted
+            && noted != version
+        {
+            let _ = partial.remove().await;
+        }
+        if noted != Some(Some(version.to_string())) {
+            let mut file = note.open(Access::Create).await?;
+            let text = format!("{}\n{}", relative.to_string_lossy(), version);
+            tokio::io::AsyncWriteExt::write_all(&mut file, text.as_bytes()).await?;
+        }
+        Ok(partial)
+    }
+
+    /// Drops the note once a partial has been moved into place.
+    pub async fn finished(&self, partial: &Entry) {
+        let _ = note_entry(partial).remove().await;
+    }
+
+    pub fn list(&self) -> Result<Vec<Partial>> {
+        let root = self.base.join(STAGING_DIR);
+        if !root.exists() {
+            return Ok(Vec::new());
+        }
+        let mut partials = Vec::new();
+        for session in std::fs::read_dir(&root)? {
+            let session = session?;
+            if !session.file_type()?.is_dir() {
+                continue;
+            }
+            let session_name = session.file_name().to_string_lossy().into_owned();
+            for entry in std::fs::read_dir(session.path())? {
+                let entry = entry?;
+                let path = entry.path();
+                let meta = entry.metadata()?;
+                if !meta.is_file() || path.extension().is_some_and(|ext| ext == "path") {
+                    continue;
+                }
+                let file = std::fs::read_to_string(note_path(&path))
+                    .ok()
+                    .and_then(|text| text.lines().next().map(str::to_string))
+                    .unwrap_or_else(|| entry.file_name().to_string_lossy().into_owned());
+                let age = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.elapsed().ok())
+                    .unwrap_or_default();
+                partials.push(Partial {
+                    session: session_name.clone(),
+                    file,
+                    size: meta.len(),
+                    age,
+                    path,
+                });
+            }
+        }
+        partials.sort_by(|a, b| (&a.session, &a.file).cmp(&(&b.session, &b.file)));
+        Ok(partials)
+    }
+
+    /// Deletes partials untouched for at least `older_than` (all of them if
+    /// `None`), optionally only in one session. Returns how many files and
+    /// bytes were removed.
+    pub fn purge(
+        &self,
+        older_than: Option<Duration>,
+        session: Option<&str>,
+    ) -> Result<(usize, u64)> {
+        let mut removed = (0, 0);
+        for partial in self.list()? {
+            if session.is_some_and(|s| s != partial.session)
+                || older_than.is_some_and(|limit| partial.age < limit)
+            {
+                continue;
+            }
+            std::fs::remove_file(&partial.path)?;
+            let _ = std::fs::remove_file(note_path(&partial.path));
+            removed.0 += 1;
+            removed.1 += partial.size;
+        }
+        // Tidy up notes left without a partial and sessions left empty. A
+        // fresh note may belong to a partial that is about to be created.
+        let root = self.base.join(STAGING_DIR);
+        if root.exists() {
+            for session in std::fs::read_dir(&root)? {
+                let dir = session?.path();
+                if !dir.is_dir() {
+                    continue;
+                }
+                for entry in std::fs::read_dir(&dir)?.flatten() {
+                    let path = entry.path();
+                    let age = entry
+                        .metadata()
+                        .ok()
+                        .and_then(|meta| meta.modified().ok())
+                        .and_then(|t| t.elapsed().ok())
+                        .unwrap_or_default();
+                    if path.extension().is_some_and(|ext| ext == "path")
+                        && !path.with_extension("").exists()
+                        && older_than.is_none_or(|limit| age >= limit)
+                    {
+                        let _ = std::fs::remove_file(&path);
+                    }
+                }
+                let _ = std::fs::remove_dir(&dir); // only succeeds when empty
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn note_path(partial: &Path) -> PathBuf {
+    partial.with_extension("path")
+}
+
+fn note_entry(partial: &Entry) -> Entry {
+    let mut name = partial.name.clone();
+    name.push(".path");
+    partial.dir.entry(name)
+}
+
+async fn read_note(note: &Entry) -> std::io::Result<String> {
+    let mut text = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut note.open(Access::Read).await?, &mut text).await?;
+    Ok(text)
+}
+
+/// 64-bit FNV-1a; stable across runs and platforms, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_partial_of_another_version_is_dropped() {
+        let base = std::env::temp_dir().join(format!("send-staging-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+        let staging = Staging::new(&base);
+        let relative = Path::new("dir/file.bin");
+
+        let partial = staging.partial_path("s", relative, "10 100").await.unwrap();
+        let partial = partial.path();
+        std::fs::write(&partial, b"12345").unwrap();
+        // Same version: kept for resuming
+        let again = staging.partial_path("s", relative, "10 100").await.unwrap();
+        assert_eq!(again.path(), partial);
+        assert!(partial.exists());
+        // The source changed: starts over
+        staging.partial_path("s", relative, "12 200").await.unwrap();
+        assert!(!partial.exists());
+        let listed = staging.list().unwrap();
+        assert!(listed.is_empty());
+
+        // Notes written before versions were recorded still resume
+        std::fs::write(&partial, b"123").unwrap();
+        std::fs::write(note_path(&partial), "dir/file.bin").unwrap();
+        staging.partial_path("s", relative, "12 200").await.unwrap();
+        assert!(partial.exists());
+        let listed = staging.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].file, "dir/file.bin");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
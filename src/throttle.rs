@@ -0,0 +1,210 @@
+use chrono::Timelike;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Token bucket for capping throughput. Callers report bytes as they move
+/// them and sleep for the returned delay whenever they run ahead of the rate.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        // Allow one buffer's worth of burst so the 1MB copy loops don't stall
+        let burst = rate.max(1024.0 * 1024.0);
+        RateLimiter {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `bytes` from the bucket and returns how long to wait before
+    /// moving more. The bucket may go into debt; the delay pays it back.
+    pub fn reserve(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Parses a rate such as `50M/s`, `512K`, `1.5GB/s` or `1000000` into bytes
+/// per second. Suffixes are binary (1K = 1024 bytes), matching the sizes
+/// shown in the progress output. `off`, `full` and `0` mean unlimited and
+/// come back as 0.
+pub fn parse_rate(input: &str) -> Result<u64, String> {
+    let s = input.trim();
+    if ["off", "full", "unlimited"]
+        .iter()
+        .any(|word| s.eq_ignore_ascii_case(word))
+    {
+        return Ok(0);
+    }
+    let s = s.strip_suffix("/s").unwrap_or(s);
+    let s = s
+        .strip_suffix('B')
+        .or_else(|| s.strip_suffix('b'))
+        .unwrap_or(s);
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024.0),
+        Some('M') => (&s[..s.len() - 1], 1024.0 * 1024.0),
+        Some('G') => (&s[..s.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (s, 1.0),
+    };
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid rate {:?} (expected e.g. 50M/s)", input))?;
+    if value < 0.0 {
+        return Err(format!("rate {:?} can't be negative", input));
+    }
+    Ok((value * multiplier) as u64)
+}
+
+/// Time-of-day bandwidth windows, e.g. `08:00-18:00=10M/s,18:00-08:00=full`.
+/// Windows may wrap past midnight; the first matching one wins.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    source: String,
+}
+
+#[derive(Clone, Debug)]
+struct Window {
+    start: u32, // minutes since midnight
+    end: u32,
+    rate: u64,
+}
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Rate for the given minute of the day, if a window covers it.
+    fn rate_at(&self, minute: u32) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|w| {
+                if w.start <= w.end {
+                    (w.start..w.end).contains(&minute)
+                } else {
+                    minute >= w.start || minute < w.end
+                }
+            })
+            .map(|w| w.rate)
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("off") {
+            // Lets `resume --schedule off` drop a saved schedule
+            return Ok(Schedule {
+                windows: Vec::new(),
+                source: String::new(),
+            });
+        }
+        let mut windows = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (span, rate) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected HH:MM-HH:MM=RATE, got {:?}", entry))?;
+            let (start, end) = span
+                .split_once('-')
+                .ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", span))?;
+            windows.push(Window {
+                start: parse_time(start)?,
+                end: parse_time(end)?,
+                rate: parse_rate(rate)?,
+            });
+        }
+        if windows.is_empty() {
+            return Err("schedule is empty".into());
+        }
+        Ok(Schedule {
+            windows,
+            source: s.trim().to_string(),
+        })
+    }
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let (h, m) = s.split_once(':').unwrap_or((s, "0"));
+    match (h.parse::<u32>(), m.parse::<u32>()) {
+        (Ok(h), Ok(m)) if h <= 24 && m < 60 && h * 60 + m <= 24 * 60 => Ok(h * 60 + m),
+        _ => Err(format!("invalid time {:?} (expected HH:MM)", s)),
+    }
+}
+
+/// Client-side bandwidth control: a fixed limit, optionally overridden by a
+/// schedule depending on the local time. 0 means unlimited.
+pub struct Throttle {
+    limit: u64,
+    schedule: Option<Schedule>,
+    current: u64,
+    limiter: Option<RateLimiter>,
+}
+
+impl Throttle {
+    pub fn new(limit: u64, schedule: Option<Schedule>) -> Self {
+        Throttle {
+            limit,
+            schedule,
+            current: 0,
+            limiter: None,
+        }
+    }
+
+    fn rate_now(&self) -> u64 {
+        let now = chrono::Local::now();
+        self.schedule
+            .as_ref()
+            .and_then(|s| s.rate_at(now.hour() * 60 + now.minute()))
+            .unwrap_or(self.limit)
+    }
+
+    /// Largest chunk to move at once, so that one chunk never takes much
+    /// longer than a fraction of a second at slow rates.
+    pub fn chunk_size(&self, max: usize) -> usize {
+        match self.current {
+            0 => max,
+            rate => max.min((rate / 4).max(16 * 1024) as usize),
+        }
+    }
+
+    /// Accounts for `bytes` just sent and returns how long to pause.
+    pub fn reserve(&mut self, bytes: u64) -> Duration {
+        let rate = self.rate_now();
+        if rate != self.current {
+            // Start the new rate with a fresh bucket
+            self.current = rate;
+            self.limiter = (rate > 0).then(|| RateLimiter::new(rate));
+        }
+        match &mut self.limiter {
+            Some(limiter) => limiter.reserve(bytes),
+            None => Duration::ZERO,
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! An io_uring storage backend for the server's write path (Linux). One
+//! thread owns the ring and serves every session: a small file goes in as a
+//! single linked chain (open, write, optional fsync, close) rather than a
+//! trip through tokio's blocking pool for each step, and renames go through
+//! it too. A session waits for each of its jobs in turn, so what shares a
+//! submission is the work of sessions running at the same time. Elsewhere
+//! `Ring::new` reports `Unsupported` and the server keeps using tokio's file
+//! I/O.
+
+use crate::paths::Entry;
+use std::io;
+
+/// Which file I/O the server uses to write what it receives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum IoBackend {
+    /// tokio's file API (thread pool)
+    Tokio,
+    /// io_uring where the kernel supports it, else tokio
+    IoUring,
+}
+
+/// Handle to the ring thread. Cheap to clone; the thread exits once every
+/// handle is gone and its work is done.
+#[derive(Clone)]
+pub struct Ring {
+    #[cfg(target_os = "linux")]
+    jobs: std::sync::mpsc::Sender<imp::Job>,
+}
+
+#[cfg(target_os = "linux")]
+impl Ring {
+    pub fn new() -> io::Result<Self> {
+        let ring = imp::setup()?;
+        let (jobs, queue) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("io-uring".into())
+            .spawn(move || imp::run(ring, queue))?;
+        Ok(Ring { jobs })
+    }
+
+    /// Creates (or truncates) `file` and writes the first `len` bytes of
+    /// `data` to it, syncing it to disk too if `sync` is set. The buffer is
+    /// handed back for reuse whatever happens.
+    pub async fn write_file(
+        &self,
+        file: &Entry,
+        data: Vec<u8>,
+        len: usize,
+        sync: bool,
+    ) -> (Vec<u8>, io::Result<()>) {
+        let name = match imp::c_name(file) {
+            Ok(name) => name,
+            Err(e) => return (data, Err(e)),
+        };
+        let (done, result) = tokio::sync::oneshot::channel();
+        let job = imp::Job {
+            op: imp::Op::Write {
+                dir: file.dir.clone(),
+                name,
+                data,
+                len,
+                sync,
+            },
+            done,
+        };
+        if let Err(std::sync::mpsc::SendError(job)) = self.jobs.send(job) {
+            let imp::Op::Write { data, .. } = job.op else {
+                unreachable!()
+            };
+            return (data, Err(imp::stopped()));
+        }
+        match result.await {
+            Ok(imp::Done { data, result }) => (data.unwrap_or_default(), result),
+            Err(_) => (Vec::new(), Err(imp::stopped())),
+        }
+    }
+
+    pub async fn rename(&self, from: &Entry, to: &Entry) -> io::Result<()> {
+        let op = imp::Op::Rename {
+            from_dir: from.dir.clone(),
+            from: imp::c_name(from)?,
+            to_dir: to.dir.clone(),
+            to: imp::c_name(to)?,
+        };
+        let (done, result) = tokio::sync::oneshot::channel();
+        self.jobs
+            .send(imp::Job { op, done })
+            .map_err(|_| imp::stopped())?;
+        result.await.map_err(|_| imp::stopped())?.result
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Ring {
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub async fn write_file(
+        &self,
+        _file: &Entry,
+        data: Vec<u8>,
+        _len: usize,
+        _sync: bool,
+    ) -> (Vec<u8>, io::Result<()>) {
+        (data, Err(io::ErrorKind::Unsupported.into()))
+    }
+
+    pub async fn rename(&self, _from: &Entry, _to: &Entry) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use crate::paths::{Dir, Entry};
+    use io_uring::{IoUring, Probe, opcode, squeue, types};
+    use std::collections::{HashMap, VecDeque};
+    use std::ffi::CString;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::sync::mpsc::{Receiver, TryRecvError};
+
+    /// Submission queue size; at most a quarter of it in write chains.
+    const ENTRIES: u32 = 256;
+    /// Registered file slots, one per write chain in flight.
+    const SLOTS: u32 = 64;
+
+    /// Names are relative to the directories the ops hold open.
+    pub enum Op {
+        Write {
+            dir: Dir,
+            name: CString,
+            data: Vec<u8>,
+            len: usize,
+            sync: bool,
+        },
+        Rename {
+            from_dir: Dir,
+            from: CString,
+            to_dir: Dir,
+            to: CString,
+        },
+    }
+
+    pub struct Job {
+        pub op: Op,
+        pub done: tokio::sync::oneshot::Sender<Done>,
+    }
+
+    pub struct Done {
+        pub data: Option<Vec<u8>>,
+        pub result: io::Result<()>,
+    }
+
+    /// A job whose entries are in the ring. It owns the names, directories
+    /// and buffer the kernel is reading from until every entry has completed.
+    struct Running {
+        job: Job,
+        slot: Option<u32>,
+        /// Results by position in the chain
+        results: Vec<Option<i32>>,
+    }
+
+    pub fn c_name(file: &Entry) -> io::Result<CString> {
+        use std::os::unix::ffi::OsStrExt;
+        CString::new(file.name.as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+    }
+
+    pub fn stopped() -> io::Error {
+        io::Error::other("io_uring thread stopped")
+    }
+
+    /// Builds the ring, checking the kernel has everything we use: the
+    /// opcodes and sparse file registration (5.19+).
+    pub fn setup() -> io::Result<IoUring> {
+        let ring = IoUring::new(ENTRIES)?;
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        let needed = [
+            opcode::OpenAt::CODE,
+            opcode::Write::CODE,
+            opcode::Fsync::CODE,
+            opcode::Close::CODE,
+            opcode::RenameAt::CODE,
+        ];
+        if !needed.iter().all(|op| probe.is_supported(*op)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "kernel lacks needed io_uring operations",
+            ));
+        }
+        ring.submitter().register_files_sparse(SLOTS)?;
+        Ok(ring)
+    }
+
+    pub fn run(mut ring: IoUring, queue: Receiver<Job>) {
+        let mut free_slots: Vec<u32> = (0..SLOTS).rev().collect();
+        let mut waiting: VecDeque<Job> = VecDeque::new();
+        let mut running: HashMap<u64, Running> = HashMap::new();
+        let mut next_id = 0u64;
+        let mut closed = false;
+        loop {
+            // Only sleep on the queue when the ring has nothing to report
+            if running.is_empty() && waiting.is_empty() {
+                if closed {
+                    return;
+                }
+                match queue.recv() {
+                    Ok(job) => waiting.push_back(job),
+                    Err(_) => return,
+                }
+            }
+            loop {
+                match queue.try_recv() {
+                    Ok(job) => waiting.push_back(job),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        closed = true;
+                        break;
+                    }
+                }
+            }
+
+            while let Some(job) = waiting.front() {
+                let slot = match job.op {
+                    Op::Write { .. } => match free_slots.pop() {
+                        Some(slot) => Some(slot),
+                        None => break,
+                    },
+                    Op::Rename { .. } => None,
+                };
+                let id = next_id;
+                next_id += 1;
+                let entries = entries(&job.op, slot, id);
+                let mut sq = ring.submission();
+                if sq.capacity() - sq.len() < entries.len() {
+                    free_slots.extend(slot);
+                    break;
+                }
+                for entry in &entries {
+                    // SAFETY: the names and buffer the entries point into
+                    // live in `running` until all of their completions are in
+                    unsafe { sq.push(entry).expect("checked for room above") };
+                }
+                drop(sq);
+                let job = waiting.pop_front().expect("peeked above");
+                running.insert(
+                    id,
+                    Running {
+                        job,
+                        slot,
+                        results: vec![None; entries.len()],
+                    },
+                );
+            }
+
+            if let Err(e) = ring.submit_and_wait(1)
+                && !matches!(e.raw_os_error(), Some(libc::EINTR | libc::EBUSY))
+            {
+                // The ring is unusable; fail everything so callers see it
+                for (_, job) in running.drain() {
+                    let _ = job.job.done.send(finish(job.job.op, Err(stopped())));
+                }
+                for job in waiting.drain(..) {
+                    let _ = job.done.send(finish(job.op, Err(stopped())));
+                }
+                return;
+            }
+
+            for cqe in ring.completion() {
+                let (id, index) = (cqe.user_data() >> 3, (cqe.user_data() & 7) as usize);
+                let Some(job) = running.get_mut(&id) else {
+                    continue;
+                };
+                job.results[index] = Some(cqe.result());
+                if job.results.iter().all(Option::is_some) {
+                    let job = running.remove(&id).expect("looked up above");
+                    // A chain cut short can leave a file open in the slot;
+                    // the next open into it replaces (and closes) that file
+                    free_slots.extend(job.slot);
+                    let result = outcome(&job.job.op, &job.results);
+                    let _ = job.job.done.send(finish(job.job.op, result));
+                }
+            }
+        }
+    }
+
+    /// The ring entries for one job, tagged with `id` and their position.
+    fn entries(op: &Op, slot: Option<u32>, id: u64) -> Vec<squeue::Entry> {
+        let chain = match op {
+            Op::Write {
+                dir,
+                name,
+                data,
+                len,
+                sync,
+            } => {
+                let slot = slot.expect("writes get a slot");
+                let file = types::Fixed(slot);
+                let target =
+                    types::DestinationSlot::try_from_slot_target(slot).expect("slot is in range");
+                let mut chain = vec![
+                    // No O_CLOEXEC: it isn't allowed for ring-only descriptors
+                    opcode::OpenAt::new(types::Fd(dir.as_raw_fd()), name.as_ptr())
+                        .file_index(Some(target))
+                        .flags(libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW)
+                        .mode(0o666)
+                        .build(),
+                    opcode::Write::new(file, data.as_ptr(), *len as u32)
+                        .offset(0)
+                        .build(),
+                ];
+                if *sync {
+                    chain.push(opcode::Fsync::new(file).build());
+                }
+                chain.push(opcode::Close::new(file).build());
+                chain
+            }
+            Op::Rename {
+                from_dir,
+                from,
+                to_dir,
+                to,
+            } => {
+                let (from_dir, to_dir) = (from_dir.as_raw_fd(), to_dir.as_raw_fd());
+                vec![
+                    opcode::RenameAt::new(
+                        types::Fd(from_dir),
+                        from.as_ptr(),
+                        types::Fd(to_dir),
+                        to.as_ptr(),
+                    )
+                    .build(),
+                ]
+            }
+        };
+        let last = chain.len() - 1;
+        chain
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let entry = entry.user_data(id << 3 | i as u64);
+                match i < last {
+                    true => entry.flags(squeue::Flags::IO_LINK),
+                    false => entry,
+                }
+            })
+            .collect()
+    }
+
+    /// The first real failure in a chain; the steps after it only report
+    /// that they were cancelled. A short write counts as a failure.
+    fn outcome(op: &Op, results: &[Option<i32>]) -> io::Result<()> {
+        for (i, result) in results.iter().enumerate() {
+            let result = result.expect("all completions are in");
+            if result < 0 && result != -libc::ECANCELED {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            if let Op::Write { len, .. } = op
+                && i == 1
+                && result >= 0
+                && result as usize != *len
+            {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        match results.contains(&Some(-libc::ECANCELED)) {
+            true => Err(io::Error::from_raw_os_error(libc::ECANCELED)),
+            false => Ok(()),
+        }
+    }
+
+    fn finish(op: Op, result: io::Result<()>) -> Done {
+        let data = match op {
+            Op::Write { data, .. } => Some(data),
+            Op::Rename { .. } => None,
+        };
+        Done { data, result }
+    }
+}
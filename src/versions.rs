@@ -0,0 +1,178 @@
+//! Earlier versions of files the server overwrote. Each file's versions live
+//! in `.send-versions/<relative path>/<timestamp>` under the destination
+//! root, so they stay out of the way but are easy to find by hand too.
+
+use crate::durable;
+use crate::paths::{self, Access, Dir, Entry};
+use crate::staging::Staging;
+use anyhow::{Result, anyhow};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+pub const VERSIONS_DIR: &str = ".send-versions";
+
+/// Staging session restores are copied through.
+const RESTORE_SESSION: &str = "restore";
+
+/// Version names sort in the order they were taken.
+const STAMP_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+/// How many old versions to keep. Both limits apply when both are set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    /// Keep at most this many versions of each file
+    pub keep: Option<usize>,
+    /// Drop versions older than this many days
+    pub days: Option<u64>,
+}
+
+pub struct Version {
+    pub name: String,
+    pub size: u64,
+    pub path: PathBuf,
+}
+
+pub struct VersionStore {
+    base: PathBuf,
+    retention: Retention,
+}
+
+impl VersionStore {
+    pub fn new(base: &Path, retention: Retention) -> Self {
+        VersionStore {
+            base: base.to_path_buf(),
+            retention,
+        }
+    }
+
+    /// Moves `file` (a file under the destination root) into the store and
+    /// prunes that file's versions.
+    pub async fn save(&self, file: &Entry) -> Result<PathBuf> {
+        let path = file.path();
+        let relative = path.strip_prefix(&self.base)?;
+        let dir = paths::create_dirs(&self.base, &Path::new(VERSIONS_DIR).join(relative)).await?;
+        let version = dir.entry(Local::now().format(STAMP_FORMAT).to_string());
+        file.rename(&version).await?;
+        self.prune(relative, &dir).await?;
+        Ok(version.path())
+    }
+
+    /// Versions of `relative`, newest first.
+    pub async fn list(&self, relative: &Path) -> Result<Vec<Version>> {
+        let dir = self.base.join(VERSIONS_DIR).join(relative);
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if meta.is_file() && parse_stamp(&name).is_some() {
+                versions.push(Version {
+                    name,
+                    size: meta.len(),
+                    path: entry.path(),
+                });
+            }
+        }
+        versions.sort_by(|a, b| b.name.cmp(&a.name));
+        Ok(versions)
+    }
+
+    /// Every path that has versions stored, with how many.
+    pub fn list_all(&self) -> Result<Vec<(String, usize)>> {
+        let root = self.base.join(VERSIONS_DIR);
+        if !root.exists() {
+            return Ok(Vec::new());
+        }
+        let mut counts = std::collections::BTreeMap::new();
+        for entry in walkdir::WalkDir::new(&root) {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy();
+            if !entry.file_type().is_file() || parse_stamp(&name).is_none() {
+                continue;
+            }
+            let Some(dir) = entry.path().parent() else {
+                continue;
+            };
+            let relative = dir
+                .strip_prefix(&root)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            *counts.entry(relative).or_insert(0) += 1;
+        }
+        Ok(counts.into_iter().collect())
+    }
+
+    /// Puts a stored version back at `relative`. The file currently there is
+    /// saved as a version first, so a restore can itself be undone.
+    pub async fn restore(&self, relative: &Path, name: Option<&str>) -> Result<Version> {
+        let versions = self.list(relative).await?;
+        let version = match name {
+            Some(name) => versions.into_iter().find(|v| v.name == name),
+            None => versions.into_iter().next(),
+        }
+        .ok_or_else(|| anyhow!("No such version of {}", relative.display()))?;
+
+        let parent = paths::create_parent_dirs(&self.base, relative).await?;
+        let name = relative
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file name", relative.display()))?;
+        let target = parent.entry(name);
+        let existing = match fs::symlink_metadata(target.path()).await {
+            Ok(meta) if meta.is_file() => true,
+            Ok(_) => {
+                return Err(anyhow!("{} is not a regular file", target.path().display()));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+
+        // Copied and synced with the partials first, as a received file is,
+        // so an interrupted restore leaves the current file alone
+        let staging = Staging::new(&self.base);
+        let temp = staging
+            .partial_path(RESTORE_SESSION, relative, &version.name)
+            .await?;
+        let mut source = fs::File::open(&version.path).await?;
+        let mut dest = temp.open(Access::Create).await?;
+        tokio::io::copy(&mut source, &mut dest).await?;
+        dest.sync_all().await?;
+        drop(dest);
+
+        if existing {
+            self.save(&target).await?;
+        }
+        temp.rename(&target).await?;
+        durable::sync_dir(&parent).await?;
+        staging.finished(&temp).await;
+        Ok(version)
+    }
+
+    /// Drops the versions of `relative`, kept in `dir`, that the retention
+    /// settings no longer cover.
+    async fn prune(&self, relative: &Path, dir: &Dir) -> Result<()> {
+        let keep = self.retention.keep.unwrap_or(usize::MAX);
+        let cutoff = self
+            .retention
+            .days
+            .map(|days| Local::now() - chrono::Duration::days(days as i64));
+        for (i, version) in self.list(relative).await?.into_iter().enumerate() {
+            let expired = cutoff.is_some_and(|cutoff| {
+                parse_stamp(&version.name).is_some_and(|taken| taken < cutoff)
+            });
+            if i >= keep || expired {
+                dir.entry(&version.name).remove().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_stamp(name: &str) -> Option<chrono::DateTime<Local>> {
+    let naive = NaiveDateTime::parse_from_str(name, STAMP_FORMAT).ok()?;
+    Local.from_local_datetime(&naive).earliest()
+}
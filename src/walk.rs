@@ -0,0 +1,243 @@
+//! Walking the source tree for the scan. Directory listings (and the stat of
+//! every entry in them) are read on tokio's blocking pool, several at once,
+//! ahead of where the walk has got to. Entries still come out one directory
+//! at a time, sorted by name, parents before children, so the `files` table
+//! ends up in the same order whatever the thread count.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::task::JoinHandle;
+
+/// Listings kept ready (read or being read) per reader thread.
+const READ_AHEAD: usize = 8;
+
+pub struct Entry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: Option<i64>,
+    /// A real directory rather than a link to one; only these are entered
+    descend: bool,
+}
+
+type Listing = io::Result<Vec<io::Result<Entry>>>;
+
+pub struct Walker {
+    threads: usize,
+    /// Entries still to be yielded, one iterator per open directory
+    stack: Vec<std::vec::IntoIter<io::Result<Entry>>>,
+    /// Directory whose children come next, if the walk goes into it
+    descend: Option<PathBuf>,
+    /// Listings started, by directory
+    reads: HashMap<PathBuf, JoinHandle<Listing>>,
+    /// Directories known but not started yet, in the order they are visited
+    ahead: VecDeque<PathBuf>,
+}
+
+impl Walker {
+    pub fn new(root: &Path, threads: usize) -> Self {
+        // Like `WalkDir`, a link given as the root itself is followed
+        let root = std::fs::metadata(root).map(|meta| Entry {
+            path: root.to_path_buf(),
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            mtime: unix_mtime(&meta),
+            descend: meta.is_dir(),
+        });
+        Walker {
+            threads: threads.max(1),
+            stack: vec![vec![root].into_iter()],
+            descend: None,
+            reads: HashMap::new(),
+            ahead: VecDeque::new(),
+        }
+    }
+
+    /// The next entry. An unreadable directory shows up as one error, after
+    /// which the walk carries on without its contents.
+    pub async fn next(&mut self) -> Option<io::Result<Entry>> {
+        if let Some(dir) = self.descend.take() {
+            match self.list(dir).await {
+                Ok(children) => self.stack.push(children.into_iter()),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        loop {
+            let entry = match self.stack.last_mut()?.next() {
+                Some(entry) => entry,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            if let Ok(entry) = &entry
+                && entry.descend
+            {
+                self.descend = Some(entry.path.clone());
+            }
+            return Some(entry);
+        }
+    }
+
+    /// Doesn't go into the directory `next` just returned.
+    pub fn skip_current_dir(&mut self) {
+        if let Some(dir) = self.descend.take() {
+            // Its listing may have been started already
+            if self.reads.remove(&dir).is_none() {
+                self.forget(&dir);
+            }
+        }
+    }
+
+    fn forget(&mut self, dir: &Path) {
+        match self.ahead.front().is_some_and(|d| d == dir) {
+            true => drop(self.ahead.pop_front()),
+            false => self.ahead.retain(|d| d != dir),
+        }
+    }
+
+    /// Waits for the listing of `dir`, starting it if it isn't already, and
+    /// queues its subdirectories to be read next.
+    async fn list(&mut self, dir: PathBuf) -> io::Result<Vec<io::Result<Entry>>> {
+        let read = match self.reads.remove(&dir) {
+            Some(read) => read,
+            None => {
+                self.forget(&dir);
+                tokio::task::spawn_blocking(move || read_dir(&dir))
+            }
+        };
+        let children = read.await.map_err(io::Error::other)??;
+        let subdirs: Vec<PathBuf> = children
+            .iter()
+            .filter_map(|entry| entry.as_ref().ok())
+            .filter(|entry| entry.descend)
+            .map(|entry| entry.path.clone())
+            .collect();
+        for subdir in subdirs.into_iter().rev() {
+            self.ahead.push_front(subdir);
+        }
+        self.start_reads();
+        Ok(children)
+    }
+
+    fn start_reads(&mut self) {
+        let running = self.reads.values().filter(|r| !r.is_finished()).count();
+        let mut free = self.threads.saturating_sub(running);
+        while free > 0 && self.reads.len() < self.threads * READ_AHEAD {
+            let Some(dir) = self.ahead.pop_front() else {
+                break;
+            };
+            let path = dir.clone();
+            self.reads
+                .insert(dir, tokio::task::spawn_blocking(move || read_dir(&path)));
+            free -= 1;
+        }
+    }
+}
+
+fn read_dir(dir: &Path) -> Listing {
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        children.push(entry.and_then(|entry| {
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            // Links are recorded as what they point to but never entered
+            let meta = match file_type.is_symlink() {
+                true => std::fs::metadata(&path)?,
+                false => entry.metadata()?,
+            };
+            Ok(Entry {
+                path,
+                is_dir: meta.is_dir(),
+                size: meta.len(),
+                mtime: unix_mtime(&meta),
+                descend: file_type.is_dir(),
+            })
+        }));
+    }
+    // Sort by name; entries that failed go last, in no particular order
+    children.sort_by(|a, b| match (a, b) {
+        (Ok(a), Ok(b)) => a.path.file_name().cmp(&b.path.file_name()),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+    });
+    Ok(children)
+}
+
+pub fn unix_mtime(meta: &std::fs::Metadata) -> Option<i64> {
+    let mtime = meta.modified().ok()?;
+    let secs = mtime.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    i64::try_from(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tree wide and deep enough that reads run ahead of the walk.
+    fn tree() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("send-walk-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in ["a", "b", "c", "d"] {
+            for sub in ["x", "y", "z"] {
+                let path = root.join(dir).join(sub).join("deep");
+                std::fs::create_dir_all(&path).unwrap();
+                std::fs::write(path.join("f.txt"), b"x").unwrap();
+                std::fs::write(root.join(dir).join(sub).join("g.txt"), b"xy").unwrap();
+            }
+            std::fs::write(root.join(dir).join("h.txt"), b"xyz").unwrap();
+        }
+        root
+    }
+
+    /// Every path the walk yields, skipping directories named `skip`.
+    async fn walk(root: &Path, threads: usize, skip: Option<&str>) -> Vec<PathBuf> {
+        let mut walker = Walker::new(root, threads);
+        let mut seen = Vec::new();
+        while let Some(entry) = walker.next().await {
+            let entry = entry.unwrap();
+            if entry.is_dir && skip.is_some_and(|name| entry.path.ends_with(name)) {
+                walker.skip_current_dir();
+            }
+            seen.push(entry.path);
+        }
+        seen
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn order_is_the_same_for_any_thread_count() {
+        let root = tree();
+        let one = walk(&root, 1, None).await;
+        assert_eq!(one.len(), 1 + 4 * (2 + 3 * 4));
+        // Parents come first and siblings are sorted
+        assert_eq!(one[1], root.join("a"));
+        assert_eq!(one[2], root.join("a/h.txt"));
+        assert_eq!(one[3], root.join("a/x"));
+        for threads in [2, 8, 16] {
+            assert_eq!(walk(&root, threads, None).await, one);
+        }
+
+        // Skipping drops a directory's contents and nothing else, whether or
+        // not its listing was already read ahead
+        for skip in ["b", "y", "deep"] {
+            let expected: Vec<PathBuf> = one
+                .iter()
+                .filter(|path| {
+                    !path
+                        .strip_prefix(&root)
+                        .unwrap()
+                        .parent()
+                        .is_some_and(|parent| parent.iter().any(|part| part == skip))
+                })
+                .cloned()
+                .collect();
+            assert!(expected.len() < one.len());
+            for threads in [1, 2, 8, 16] {
+                assert_eq!(walk(&root, threads, Some(skip)).await, expected);
+            }
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+//! Linux fast paths that move file data without copying it through user
+//! space: `sendfile` from the file to the socket when sending, and `splice`
+//! from the socket through a pipe into the file when receiving. Elsewhere
+//! everything here reports `Unsupported` and callers use their buffered loops.
+
+use std::io;
+use tokio::fs::File;
+use tokio::net::TcpStream;
+
+/// Sends up to `len` bytes of `file` starting at `*offset`, advancing it.
+/// Returns 0 if the file ended early.
+#[cfg(target_os = "linux")]
+pub async fn send_file(
+    socket: &TcpStream,
+    file: &File,
+    offset: &mut u64,
+    len: usize,
+) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+    let (out_fd, in_fd) = (socket.as_raw_fd(), file.as_raw_fd());
+    socket
+        .async_io(Interest::WRITABLE, || {
+            let mut off = *offset as libc::off_t;
+            // SAFETY: both descriptors are open and `off` is a valid pointer
+            let n = unsafe { libc::sendfile(out_fd, in_fd, &mut off, len) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            *offset = off as u64;
+            Ok(n as usize)
+        })
+        .await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_file(
+    _socket: &TcpStream,
+    _file: &File,
+    _offset: &mut u64,
+    _len: usize,
+) -> io::Result<usize> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Errors that mean the kernel can't do this for these descriptors, as
+/// opposed to the transfer itself failing. Nothing has moved when they occur.
+pub fn is_unsupported(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(code) = e.raw_os_error() {
+        return matches!(code, libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP);
+    }
+    false
+}
+
+/// `sendfile` reports a failed read of the source file as EIO; anything else
+/// it returns is about the socket.
+pub fn is_file_error(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    return e.raw_os_error() == Some(libc::EIO);
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = e;
+        false
+    }
+}
+
+/// A pipe for splicing socket data into a file. Data goes socket -> pipe
+/// with `fill`, then pipe -> file with `drain`.
+pub struct Splicer {
+    #[cfg(target_os = "linux")]
+    read: std::os::fd::OwnedFd,
+    #[cfg(target_os = "linux")]
+    write: std::os::fd::OwnedFd,
+}
+
+/// Pipes hold 64K by default; a bigger one means fewer trips per chunk.
+#[cfg(target_os = "linux")]
+const PIPE_SIZE: libc::c_int = 1024 * 1024;
+
+#[cfg(target_os = "linux")]
+impl Splicer {
+    pub fn new() -> io::Result<Self> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 returns
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both are fresh descriptors we own
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        // Best effort; unprivileged users may be capped lower
+        // SAFETY: plain fcntl on a descriptor we own
+        unsafe { libc::fcntl(fds[1], libc::F_SETPIPE_SZ, PIPE_SIZE) };
+        Ok(Splicer { read, write })
+    }
+
+    /// Moves up to `len` bytes from the socket into the (empty) pipe.
+    /// Returns 0 when the peer has closed the connection.
+    pub async fn fill(&self, socket: &TcpStream, len: usize) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+        let (in_fd, out_fd) = (socket.as_raw_fd(), self.write.as_raw_fd());
+        let len = len.min(PIPE_SIZE as usize);
+        socket
+            .async_io(Interest::READABLE, || {
+                // SAFETY: both descriptors are open; null offsets are allowed
+                // for sockets and pipes
+                let n = unsafe {
+                    libc::splice(
+                        in_fd,
+                        std::ptr::null_mut(),
+                        out_fd,
+                        std::ptr::null_mut(),
+                        len,
+                        libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+                    )
+                };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            })
+            .await
+    }
+
+    /// Writes the `len` bytes `fill` put in the pipe to `file` at `*offset`,
+    /// advancing it. This blocks on the disk like a plain `write` would, so
+    /// async callers should run it where blocking is allowed.
+    pub fn drain(&self, file: &File, offset: &mut u64, mut len: usize) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        while len > 0 {
+            let mut off = *offset as libc::loff_t;
+            // SAFETY: both descriptors are open and `off` is a valid pointer
+            let n = unsafe {
+                libc::splice(
+                    self.read.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    file.as_raw_fd(),
+                    &mut off,
+                    len,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            *offset = off as u64;
+            len -= n as usize;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Splicer {
+    pub fn new() -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub async fn fill(&self, _socket: &TcpStream, _len: usize) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn drain(&self, _file: &File, _offset: &mut u64, _len: usize) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+/// Checks that `probe`, an empty scratch file, can be spliced into by
+/// splicing one byte into it. Some filesystems (FUSE, certain network
+/// mounts) refuse.
+#[cfg(target_os = "linux")]
+pub fn splice_supported(probe: &File) -> bool {
+    use std::os::fd::AsRawFd;
+    let Ok(splicer) = Splicer::new() else {
+        return false;
+    };
+    // SAFETY: writing one byte from a valid buffer into our own pipe
+    if unsafe { libc::write(splicer.write.as_raw_fd(), b"x".as_ptr().cast(), 1) } != 1 {
+        return false;
+    }
+    splicer.drain(probe, &mut 0, 1).is_ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn splice_supported(_probe: &File) -> bool {
+    false
+}